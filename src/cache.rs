@@ -0,0 +1,219 @@
+//! Content hashes of the documents built previously, used to skip
+//! documents whose inputs have not changed since the last build.
+
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Mutex, OnceLock},
+    thread,
+};
+
+/// The name of the cache file kept in the destination directory.
+const CACHE_FILE: &str = ".mdbook-docx-cache.toml";
+
+/// The directory in the destination that converted and generated images
+/// are kept in, named by a hash of what they were made from.
+pub const IMAGE_DIR: &str = ".mdbook-docx-images";
+
+/// Set to anything other than `0` or `false` to rebuild every document.
+pub const FORCE_ENV: &str = "MDBOOK_DOCX_FORCE";
+
+/// The hash of each document's inputs from the last successful build.
+pub struct Cache {
+    path: PathBuf,
+    force: bool,
+    entries: Mutex<BTreeMap<String, String>>,
+}
+
+impl Cache {
+    pub fn load(destination: &Path, force: bool) -> Self {
+        let force =
+            force || env::var(FORCE_ENV).is_ok_and(|v| !v.is_empty() && v != "0" && v != "false");
+        let path = destination.join(CACHE_FILE);
+        let entries = fs::read_to_string(&path)
+            .ok()
+            .and_then(|s| toml::from_str(&s).ok())
+            .unwrap_or_default();
+        Self {
+            path,
+            force,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Whether `output` exists and was built from inputs with this hash.
+    pub fn is_fresh(&self, document: &Path, hash: &str, output: &Path) -> bool {
+        !self.force
+            && output.exists()
+            && self
+                .entries
+                .lock()
+                .unwrap()
+                .get(&key(document))
+                .map(String::as_str)
+                == Some(hash)
+    }
+
+    /// Record the hash of a document that was built, or forget it with `None`.
+    pub fn update(&self, document: &Path, hash: Option<String>) {
+        let mut entries = self.entries.lock().unwrap();
+        match hash {
+            Some(hash) => entries.insert(key(document), hash),
+            None => entries.remove(&key(document)),
+        };
+    }
+
+    pub fn save(&self) {
+        let entries = self.entries.lock().unwrap();
+        let result = toml::to_string(&*entries)
+            .map_err(anyhow::Error::from)
+            .and_then(|s| Ok(fs::write(&self.path, s)?));
+        match result {
+            Ok(()) => debug!("Saved the build cache to {}", self.path.display()),
+            Err(e) => warn!("Unable to save the build cache: {}", e),
+        }
+    }
+}
+
+fn key(document: &Path) -> String {
+    document.to_string_lossy().replace('\\', "/")
+}
+
+/// A hash over everything a document is built from.
+pub struct Fingerprint(Sha256);
+
+impl Fingerprint {
+    pub fn new() -> Self {
+        let mut fingerprint = Self(Sha256::new());
+        fingerprint.add("renderer", env!("CARGO_PKG_VERSION").as_bytes());
+        fingerprint.add("pandoc", pandoc_version().as_bytes());
+        fingerprint
+    }
+
+    pub fn add(&mut self, label: &str, data: &[u8]) -> &mut Self {
+        // length prefixes keep neighbouring values from running together
+        for value in [label.as_bytes(), data] {
+            self.0.update((value.len() as u64).to_le_bytes());
+            self.0.update(value);
+        }
+        self
+    }
+
+    /// Add the bytes of a file, or a marker if it cannot be read.
+    pub fn add_file(&mut self, label: &str, path: &Path) -> &mut Self {
+        match fs::read(path) {
+            Ok(data) => self.add(label, &data),
+            Err(_) => self.add(label, b"<missing>"),
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.0
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// Write a cached file, creating its directory. Documents are built
+/// concurrently, so the file is written under a name of its own and moved
+/// into place once complete.
+pub fn store(path: &Path, data: &[u8]) -> io::Result<()> {
+    let partial = path.with_extension(format!("{:?}.tmp", thread::current().id()));
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&partial, data)?;
+    fs::rename(&partial, path)
+}
+
+// the first line of `pandoc --version`, looked up once per build
+fn pandoc_version() -> &'static str {
+    static VERSION: OnceLock<String> = OnceLock::new();
+    VERSION.get_or_init(|| {
+        Command::new("pandoc")
+            .arg("--version")
+            .output()
+            .ok()
+            .and_then(|o| String::from_utf8(o.stdout).ok())
+            .and_then(|s| s.lines().next().map(str::to_string))
+            .unwrap_or_else(|| "unknown".to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{create_files, scratch_dir};
+
+    #[test]
+    fn documents_are_fresh_only_when_built_from_the_same_inputs() {
+        let dir = scratch_dir("cache");
+        let document = Path::new("guide.docx");
+        let output = dir.join("guide.docx");
+        env::remove_var(FORCE_ENV);
+
+        let cache = Cache::load(&dir, false);
+        cache.update(document, Some("abc".to_string()));
+        // the output is missing
+        assert!(!cache.is_fresh(document, "abc", &output));
+        fs::write(&output, b"docx").unwrap();
+        assert!(cache.is_fresh(document, "abc", &output));
+        assert!(!cache.is_fresh(document, "abd", &output));
+        assert!(!cache.is_fresh(Path::new("other.docx"), "abc", &output));
+        cache.save();
+
+        // entries are kept between builds
+        assert!(Cache::load(&dir, false).is_fresh(document, "abc", &output));
+        assert!(!Cache::load(&dir, true).is_fresh(document, "abc", &output));
+        for (value, force) in [("1", true), ("yes", true), ("0", false), ("false", false)] {
+            env::set_var(FORCE_ENV, value);
+            let cache = Cache::load(&dir, false);
+            assert_eq!(
+                !cache.is_fresh(document, "abc", &output),
+                force,
+                "{}",
+                value
+            );
+        }
+        env::remove_var(FORCE_ENV);
+
+        cache.update(document, None);
+        assert!(!cache.is_fresh(document, "abc", &output));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn fingerprints_change_with_file_contents() {
+        let dir = scratch_dir("fingerprint");
+        create_files(&dir, &["img/a.png"]);
+        let image = dir.join("img/a.png");
+        let hash = || {
+            let mut fingerprint = Fingerprint::new();
+            fingerprint.add("content", b"![](img/a.png)");
+            fingerprint.add_file("img/a.png", &image);
+            fingerprint.finish()
+        };
+
+        let first = hash();
+        assert_eq!(hash(), first);
+        fs::write(&image, b"edited").unwrap();
+        let edited = hash();
+        assert_ne!(edited, first);
+        fs::remove_file(&image).unwrap();
+        assert_ne!(hash(), edited);
+
+        // values do not run together
+        let mut a = Fingerprint::new();
+        a.add("ab", b"c");
+        let mut b = Fingerprint::new();
+        b.add("a", b"bc");
+        assert_ne!(a.finish(), b.finish());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
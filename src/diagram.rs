@@ -0,0 +1,226 @@
+//! Rendering of diagram code blocks, such as Graphviz and Mermaid, to images
+//! with a local command.
+//!
+//! A command reads the diagram source from its standard input, or from the
+//! file in place of an `{input}` argument, and writes the image to its
+//! standard output, or to the file in place of an `{output}` argument. Images
+//! are kept in the image cache, named by a hash of the command and the
+//! source, so the command only runs for new or changed diagrams.
+
+use crate::cache::{self, Fingerprint};
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::LazyLock,
+    thread,
+};
+
+// the formats an image may be produced in, by the start of the file
+const FORMATS: [(&str, &[u8]); 4] = [
+    ("png", b"\x89PNG"),
+    ("jpg", b"\xff\xd8\xff"),
+    ("gif", b"GIF8"),
+    ("svg", b"<"),
+];
+
+static CAPTION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"\bcaption="([^"]*)""#).unwrap());
+
+/// Renders the diagrams of a document.
+pub struct Renderer<'a> {
+    commands: &'a BTreeMap<String, String>,
+    dir: PathBuf,
+    // programs found missing, whose diagrams are left as code blocks
+    missing: BTreeSet<String>,
+}
+
+impl<'a> Renderer<'a> {
+    /// A renderer running the command set for each code block language and
+    /// keeping the images in `dir`.
+    pub fn new(commands: &'a BTreeMap<String, String>, dir: PathBuf) -> Self {
+        Self {
+            commands,
+            dir,
+            missing: BTreeSet::new(),
+        }
+    }
+
+    /// The image rendered from a code block of the language, or `None` when
+    /// the language has no command or its program was already found missing.
+    pub fn render(&mut self, language: &str, source: &str) -> Result<Option<PathBuf>> {
+        let Some(command) = self.commands.get(language) else {
+            return Ok(None);
+        };
+        let args: Vec<&str> = command.split_whitespace().collect();
+        let Some((program, args)) = args.split_first() else {
+            bail!("The command for {} diagrams is empty", language);
+        };
+        if self.missing.contains(*program) {
+            return Ok(None);
+        }
+
+        let mut fingerprint = Fingerprint::new();
+        fingerprint
+            .add("command", command.as_bytes())
+            .add("source", source.as_bytes());
+        let hash = fingerprint.finish();
+        let cached = FORMATS
+            .iter()
+            .map(|(extension, _)| self.dir.join(format!("{}.{}", hash, extension)))
+            .find(|path| path.exists());
+        if let Some(path) = cached {
+            return Ok(Some(path));
+        }
+
+        let image = match run(program, args, source, &self.dir, &hash, language) {
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                self.missing.insert(program.to_string());
+                bail!("{} was not found", program);
+            }
+            result => result.with_context(|| format!("Unable to run `{}`", command))?,
+        };
+        let start = image.trim_ascii_start();
+        let Some((extension, _)) = FORMATS.iter().find(|(_, magic)| start.starts_with(magic))
+        else {
+            bail!(
+                "`{}` did not produce a PNG, JPEG, GIF or SVG image",
+                command
+            );
+        };
+        let path = self.dir.join(format!("{}.{}", hash, extension));
+        cache::store(&path, &image)
+            .with_context(|| format!("Unable to write {}", path.display()))?;
+        Ok(Some(path))
+    }
+}
+
+/// The language and the caption of a code block from its info string, as in
+/// ```` ```dot caption="Request flow" ````.
+pub fn parse_info(info: &str) -> (&str, Option<&str>) {
+    let language = info.split_whitespace().next().unwrap_or_default();
+    let caption = CAPTION
+        .captures(info)
+        .and_then(|c| c.get(1))
+        .map(|m| m.as_str());
+    (language, caption)
+}
+
+// run a diagram command, returning the image it produced
+fn run(
+    program: &str,
+    args: &[&str],
+    source: &str,
+    dir: &Path,
+    hash: &str,
+    language: &str,
+) -> std::io::Result<Vec<u8>> {
+    // files for `{input}` and `{output}`, named for the thread as documents
+    // are built concurrently. The output is named as a PNG since some tools
+    // pick the format from the extension.
+    let thread: String = format!("{:?}", thread::current().id())
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect();
+    let input = dir.join(format!("{}-{}.{}", hash, thread, language));
+    let output = dir.join(format!("{}-{}.output.png", hash, thread));
+    let uses_input = args.contains(&"{input}");
+    let uses_output = args.contains(&"{output}");
+    if uses_input || uses_output {
+        fs::create_dir_all(dir)?;
+    }
+    if uses_input {
+        fs::write(&input, source)?;
+    }
+    let args = args.iter().map(|arg| match *arg {
+        "{input}" => input.as_os_str().to_owned(),
+        "{output}" => output.as_os_str().to_owned(),
+        arg => arg.into(),
+    });
+
+    let result = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            // the source is written from another thread so a tool writing its
+            // output before reading all of its input cannot block on a full pipe
+            let stdin = child.stdin.take().filter(|_| !uses_input);
+            let source = source.to_string();
+            let writer = thread::spawn(move || {
+                if let Some(mut stdin) = stdin {
+                    // a tool that exits without reading its input closes the pipe early
+                    let _ = stdin.write_all(source.as_bytes());
+                }
+            });
+            let output = child.wait_with_output();
+            let _ = writer.join();
+            output
+        });
+    if uses_input {
+        let _ = fs::remove_file(&input);
+    }
+    let result = result?;
+    if !result.status.success() {
+        let _ = fs::remove_file(&output);
+        return Err(std::io::Error::other(format!(
+            "{}: {}",
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim()
+        )));
+    }
+    if uses_output {
+        let image = fs::read(&output);
+        let _ = fs::remove_file(&output);
+        image
+    } else {
+        Ok(result.stdout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch_dir;
+
+    #[test]
+    fn info_strings() {
+        assert_eq!(parse_info("dot"), ("dot", None));
+        assert_eq!(
+            parse_info(r#"dot caption="Request flow""#),
+            ("dot", Some("Request flow"))
+        );
+        assert_eq!(
+            parse_info(r#"  mermaid  {.wide} caption="""#),
+            ("mermaid", Some(""))
+        );
+        assert_eq!(parse_info("dot caption=flow"), ("dot", None));
+        assert_eq!(parse_info(""), ("", None));
+    }
+
+    #[test]
+    fn missing_programs_are_reported_once() {
+        let dir = scratch_dir("diagram-missing");
+        let commands = BTreeMap::from([(
+            "dot".to_string(),
+            "mdbook-docx-no-such-program -Tpng".to_string(),
+        )]);
+        let mut renderer = Renderer::new(&commands, dir.clone());
+
+        let error = renderer.render("dot", "digraph { a -> b }").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "mdbook-docx-no-such-program was not found"
+        );
+        // later diagrams are left as they are without trying again
+        assert_eq!(renderer.render("dot", "digraph { c }").unwrap(), None);
+        // as are languages without a command
+        assert_eq!(renderer.render("rust", "fn main() {}").unwrap(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,923 @@
+//! Post-processing of the .docx packages pandoc produces.
+
+use anyhow::{Context, Result};
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+};
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+/// The main document part of a package.
+pub const DOCUMENT_XML: &str = "word/document.xml";
+
+/// The document settings part of a package.
+pub const SETTINGS_XML: &str = "word/settings.xml";
+
+/// The text of the paragraph marking where the table of contents goes.
+pub const TOC_MARKER: &str = "MDBOOK-DOCX-TOC";
+
+/// The text of the paragraph marking where the list of figures goes.
+pub const LIST_OF_FIGURES_MARKER: &str = "MDBOOK-DOCX-LIST-OF-FIGURES";
+
+/// The text of the paragraph marking where the list of tables goes.
+pub const LIST_OF_TABLES_MARKER: &str = "MDBOOK-DOCX-LIST-OF-TABLES";
+
+/// A .docx package held in memory as its list of parts, in archive order.
+pub struct Package {
+    parts: Vec<(String, Vec<u8>)>,
+}
+
+impl Package {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("Unable to open {}", path.display()))?;
+        let mut archive = ZipArchive::new(file)
+            .with_context(|| format!("{} is not a valid .docx file", path.display()))?;
+
+        let mut parts = Vec::new();
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)?;
+            if entry.is_dir() {
+                continue;
+            }
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            parts.push((entry.name().to_string(), data));
+        }
+        Ok(Self { parts })
+    }
+
+    /// A package of the given parts, for building documents in memory.
+    #[cfg(test)]
+    pub fn from_parts(parts: &[(&str, &str)]) -> Self {
+        Self {
+            parts: parts
+                .iter()
+                .map(|(name, data)| (name.to_string(), data.as_bytes().to_vec()))
+                .collect(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file =
+            File::create(path).with_context(|| format!("Unable to create {}", path.display()))?;
+        let mut zip = ZipWriter::new(file);
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        for (name, data) in &self.parts {
+            zip.start_file(name.as_str(), options)?;
+            zip.write_all(data)?;
+        }
+        zip.finish()?;
+        Ok(())
+    }
+
+    pub fn part(&self, name: &str) -> Option<&[u8]> {
+        self.parts
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, data)| data.as_slice())
+    }
+
+    pub fn xml(&self, name: &str) -> Result<String> {
+        let data = self
+            .part(name)
+            .with_context(|| format!("The document has no {} part", name))?;
+        Ok(String::from_utf8(data.to_vec())?)
+    }
+
+    /// Replace the content of a part, adding it if it does not exist.
+    pub fn set_part(&mut self, name: &str, data: Vec<u8>) {
+        match self.parts.iter_mut().find(|(n, _)| n == name) {
+            Some(part) => part.1 = data,
+            None => self.parts.push((name.to_string(), data)),
+        }
+    }
+}
+
+/// A break inserted into the document, marked in the markdown by a paragraph
+/// holding only the break's marker text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Break {
+    Page,
+    SectionNextPage,
+    SectionOddPage,
+}
+
+impl Break {
+    const ALL: [Break; 3] = [Break::Page, Break::SectionNextPage, Break::SectionOddPage];
+
+    pub fn marker(self) -> &'static str {
+        match self {
+            Break::Page => "MDBOOK-DOCX-BREAK-PAGE",
+            Break::SectionNextPage => "MDBOOK-DOCX-BREAK-SECTION-NEXT-PAGE",
+            Break::SectionOddPage => "MDBOOK-DOCX-BREAK-SECTION-ODD-PAGE",
+        }
+    }
+
+    // the value of w:type for a section started by this break
+    fn section_type(self) -> Option<&'static str> {
+        match self {
+            Break::Page => None,
+            Break::SectionNextPage => Some("nextPage"),
+            Break::SectionOddPage => Some("oddPage"),
+        }
+    }
+}
+
+/// Replace every break marker paragraph in the document with a real Word
+/// page or section break.
+///
+/// A section's `w:type` says how that section starts, and it lives in the
+/// `w:sectPr` that ends the section. Each section break therefore carries a
+/// copy of the body's page setup with the type of the break before it, and
+/// the last section gets the type of the last break in the body `w:sectPr`.
+pub fn apply_breaks(package: &mut Package) -> Result<()> {
+    let mut xml = package.xml(DOCUMENT_XML)?;
+    let body_sect_pr = match find_body_sect_pr(&xml) {
+        Some(range) => xml[range].to_string(),
+        None => "<w:sectPr/>".to_string(),
+    };
+
+    // how the section the breaks are in started, in document order
+    let mut section_type = None;
+    while let Some((b, range)) = Break::ALL
+        .iter()
+        .filter_map(|b| Some((*b, find_paragraph(&xml, b.marker())?)))
+        .min_by_key(|(_, range)| range.start)
+    {
+        let replacement = match b.section_type() {
+            None => r#"<w:p><w:r><w:br w:type="page"/></w:r></w:p>"#.to_string(),
+            Some(next) => {
+                let sect_pr = match section_type.replace(next) {
+                    Some(started) => set_section_type(&body_sect_pr, started),
+                    None => body_sect_pr.clone(),
+                };
+                format!("<w:p><w:pPr>{}</w:pPr></w:p>", sect_pr)
+            }
+        };
+        xml.replace_range(range, &replacement);
+    }
+
+    if let Some(section_type) = section_type {
+        match find_body_sect_pr(&xml) {
+            Some(range) => {
+                let sect_pr = set_section_type(&xml[range.clone()], section_type);
+                xml.replace_range(range, &sect_pr);
+            }
+            None => {
+                let end = xml.rfind("</w:body>").context("The document has no body")?;
+                xml.insert_str(end, &set_section_type("<w:sectPr/>", section_type));
+            }
+        }
+    }
+
+    package.set_part(DOCUMENT_XML, xml.into_bytes());
+    Ok(())
+}
+
+/// Replace the marker paragraph with a title and a Word `TOC` field built
+/// from `instruction`, such as `TOC \o "1-3" \h \z \u`.
+///
+/// Word fills the field in, so it is marked dirty and the document asks
+/// Word to update its fields when it is opened.
+pub fn insert_toc(
+    package: &mut Package,
+    marker: &str,
+    title: &str,
+    instruction: &str,
+) -> Result<()> {
+    let toc = format!(
+        concat!(
+            r#"<w:sdt><w:sdtPr><w:docPartObj><w:docPartGallery w:val="Table of Contents"/>"#,
+            r#"<w:docPartUnique/></w:docPartObj></w:sdtPr><w:sdtContent>{}</w:sdtContent></w:sdt>"#
+        ),
+        field_list(title, instruction)
+    );
+    replace_marker(package, marker, &toc)?;
+    update_fields_on_open(package)
+}
+
+/// Replace the marker paragraph with a title and a `TOC` field listing
+/// captions, such as `TOC \h \z \c "Figure"`, updated the same way as
+/// [`insert_toc`].
+pub fn insert_list(
+    package: &mut Package,
+    marker: &str,
+    title: &str,
+    instruction: &str,
+) -> Result<()> {
+    replace_marker(package, marker, &field_list(title, instruction))?;
+    update_fields_on_open(package)
+}
+
+// a titled paragraph holding a dirty field for Word to fill in
+fn field_list(title: &str, instruction: &str) -> String {
+    format!(
+        concat!(
+            r#"<w:p><w:pPr><w:pStyle w:val="TOCHeading"/></w:pPr><w:r><w:t xml:space="preserve">{}</w:t></w:r></w:p>"#,
+            r#"<w:p><w:r><w:fldChar w:fldCharType="begin" w:dirty="true"/></w:r>"#,
+            r#"<w:r><w:instrText xml:space="preserve"> {} </w:instrText></w:r>"#,
+            r#"<w:r><w:fldChar w:fldCharType="separate"/></w:r>"#,
+            r#"<w:r><w:t>Update the field to show the {}.</w:t></w:r>"#,
+            r#"<w:r><w:fldChar w:fldCharType="end"/></w:r></w:p>"#
+        ),
+        escape(title),
+        escape(instruction),
+        escape(&title.to_lowercase()),
+    )
+}
+
+fn replace_marker(package: &mut Package, marker: &str, content: &str) -> Result<()> {
+    let mut xml = package.xml(DOCUMENT_XML)?;
+    let range = find_paragraph(&xml, marker)
+        .with_context(|| format!("No paragraph holding {} was found in the document", marker))?;
+    xml.replace_range(range, content);
+    package.set_part(DOCUMENT_XML, xml.into_bytes());
+    Ok(())
+}
+
+/// Number every caption paragraph in `style` as `<label> <n>: ` with a
+/// `SEQ` field, which is what a `TOC \c "<label>"` field lists. Captions
+/// that already hold a `SEQ` field are left alone.
+pub fn number_captions(package: &mut Package, style: &str, label: &str) -> Result<()> {
+    let mut xml = package.xml(DOCUMENT_XML)?;
+    let style_ref = format!(r#"<w:pStyle w:val="{}""#, style);
+    let mut number = 0;
+    let mut from = 0;
+    while let Some(at) = xml[from..].find(&style_ref).map(|i| from + i) {
+        let Some(p_pr_end) = xml[at..]
+            .find("</w:pPr>")
+            .map(|i| at + i + "</w:pPr>".len())
+        else {
+            break;
+        };
+        let p_end = xml[at..].find("</w:p>").map_or(xml.len(), |i| at + i);
+        from = p_pr_end;
+        if xml[p_pr_end..p_end].contains(" SEQ ") {
+            continue;
+        }
+        number += 1;
+        let runs = format!(
+            concat!(
+                r#"<w:r><w:t xml:space="preserve">{} </w:t></w:r>"#,
+                r#"<w:r><w:fldChar w:fldCharType="begin"/></w:r>"#,
+                r#"<w:r><w:instrText xml:space="preserve"> SEQ {} \* ARABIC </w:instrText></w:r>"#,
+                r#"<w:r><w:fldChar w:fldCharType="separate"/></w:r>"#,
+                r#"<w:r><w:t>{}</w:t></w:r>"#,
+                r#"<w:r><w:fldChar w:fldCharType="end"/></w:r>"#,
+                r#"<w:r><w:t xml:space="preserve">: </w:t></w:r>"#
+            ),
+            escape(label),
+            escape(label),
+            number
+        );
+        xml.insert_str(p_pr_end, &runs);
+        from = p_pr_end + runs.len();
+    }
+    package.set_part(DOCUMENT_XML, xml.into_bytes());
+    Ok(())
+}
+
+/// Remove the title block pandoc renders from the document metadata, the
+/// paragraphs at the start of the body in `styles`, which are in pandoc's
+/// order. Pandoc leaves out empty fields, so a style may be missing, but a
+/// paragraph out of that order ends the title block, leaving content that
+/// uses the same styles alone. With `keep_title` the title itself stays, for
+/// a title taken from the content.
+pub fn remove_title_block(package: &mut Package, styles: &[&str], keep_title: bool) -> Result<()> {
+    let mut xml = package.xml(DOCUMENT_XML)?;
+    let start = xml.find("<w:body").context("The document has no body")?;
+    let mut start = start + xml[start..].find('>').context("The document has no body")? + 1;
+    let mut expected = styles.iter().copied().peekable();
+    loop {
+        let rest = &xml[start..];
+        if !(rest.starts_with("<w:p>") || rest.starts_with("<w:p ")) {
+            break;
+        }
+        let Some(end) = rest.find("</w:p>").map(|i| i + "</w:p>".len()) else {
+            break;
+        };
+        let Some(style) = paragraph_style(&rest[..end]) else {
+            break;
+        };
+        if keep_title && style == "Title" {
+            expected.next_if_eq(&"Title");
+            start += end;
+            continue;
+        }
+        if !expected.by_ref().any(|s| s == style) {
+            break;
+        }
+        xml.replace_range(start..start + end, "");
+    }
+    package.set_part(DOCUMENT_XML, xml.into_bytes());
+    Ok(())
+}
+
+// the style of a paragraph, if it has one
+fn paragraph_style(paragraph: &str) -> Option<&str> {
+    let open = r#"<w:pStyle w:val=""#;
+    let at = paragraph.find(open)? + open.len();
+    let end = at + paragraph[at..].find('"')?;
+    Some(&paragraph[at..end])
+}
+
+/// Ask Word to update every field in the document when it is opened.
+pub fn update_fields_on_open(package: &mut Package) -> Result<()> {
+    add_setting(package, r#"<w:updateFields w:val="true"/>"#)
+}
+
+// the children of w:settings, in the order the schema requires
+const SETTINGS: [&str; 99] = [
+    "w:writeProtection",
+    "w:view",
+    "w:zoom",
+    "w:removePersonalInformation",
+    "w:removeDateAndTime",
+    "w:doNotDisplayPageBoundaries",
+    "w:displayBackgroundShape",
+    "w:printPostScriptOverText",
+    "w:printFractionalCharacterWidth",
+    "w:printFormsData",
+    "w:embedTrueTypeFonts",
+    "w:embedSystemFonts",
+    "w:saveSubsetFonts",
+    "w:saveFormsData",
+    "w:mirrorMargins",
+    "w:alignBordersAndEdges",
+    "w:bordersDoNotSurroundHeader",
+    "w:bordersDoNotSurroundFooter",
+    "w:gutterAtTop",
+    "w:hideSpellingErrors",
+    "w:hideGrammaticalErrors",
+    "w:activeWritingStyle",
+    "w:proofState",
+    "w:formsDesign",
+    "w:attachedTemplate",
+    "w:linkStyles",
+    "w:stylePaneFormatFilter",
+    "w:stylePaneSortMethod",
+    "w:documentType",
+    "w:mailMerge",
+    "w:revisionView",
+    "w:trackRevisions",
+    "w:doNotTrackMoves",
+    "w:doNotTrackFormatting",
+    "w:documentProtection",
+    "w:autoFormatOverride",
+    "w:styleLockTheme",
+    "w:styleLockQFSet",
+    "w:defaultTabStop",
+    "w:autoHyphenation",
+    "w:consecutiveHyphenLimit",
+    "w:hyphenationZone",
+    "w:doNotHyphenateCaps",
+    "w:showEnvelope",
+    "w:summaryLength",
+    "w:clickAndTypeStyle",
+    "w:defaultTableStyle",
+    "w:evenAndOddHeaders",
+    "w:bookFoldRevPrinting",
+    "w:bookFoldPrinting",
+    "w:bookFoldPrintingSheets",
+    "w:drawingGridHorizontalSpacing",
+    "w:drawingGridVerticalSpacing",
+    "w:displayHorizontalDrawingGridEvery",
+    "w:displayVerticalDrawingGridEvery",
+    "w:doNotUseMarginsForDrawingGridOrigin",
+    "w:drawingGridHorizontalOrigin",
+    "w:drawingGridVerticalOrigin",
+    "w:doNotShadeFormData",
+    "w:noPunctuationKerning",
+    "w:characterSpacingControl",
+    "w:printTwoOnOne",
+    "w:strictFirstAndLastChars",
+    "w:noLineBreaksAfter",
+    "w:noLineBreaksBefore",
+    "w:savePreviewPicture",
+    "w:doNotValidateAgainstSchema",
+    "w:saveInvalidXml",
+    "w:ignoreMixedContent",
+    "w:alwaysShowPlaceholderText",
+    "w:doNotDemarcateInvalidXml",
+    "w:saveXmlDataOnly",
+    "w:useXSLTWhenSaving",
+    "w:saveThroughXslt",
+    "w:showXMLTags",
+    "w:alwaysMergeEmptyNamespace",
+    "w:updateFields",
+    "w:hdrShapeDefaults",
+    "w:footnotePr",
+    "w:endnotePr",
+    "w:compat",
+    "w:docVars",
+    "w:rsids",
+    "m:mathPr",
+    "w:attachedSchema",
+    "w:themeFontLang",
+    "w:clrSchemeMapping",
+    "w:doNotIncludeSubdocsInStats",
+    "w:doNotAutoCompressPictures",
+    "w:forceUpgrade",
+    "w:captions",
+    "w:readModeInkLockDown",
+    "w:smartTagType",
+    "sl:schemaLibrary",
+    "w:shapeDefaults",
+    "w:doNotEmbedSmartTags",
+    "w:decimalSymbol",
+    "w:listSeparator",
+    "w14:docId",
+];
+
+/// Add an element to the settings part in its place in the schema order,
+/// unless the setting is already there.
+pub fn add_setting(package: &mut Package, element: &str) -> Result<()> {
+    let name = element[1..]
+        .split([' ', '/', '>'])
+        .next()
+        .unwrap_or_default();
+    let position = SETTINGS
+        .iter()
+        .position(|s| *s == name)
+        .with_context(|| format!("{} is not a known setting", name))?;
+    let mut xml = package.xml(SETTINGS_XML)?;
+    if find_element(&xml, name).is_some() {
+        return Ok(());
+    }
+    let at = SETTINGS[position + 1..]
+        .iter()
+        .filter_map(|follower| find_element(&xml, follower))
+        .min()
+        .or_else(|| xml.rfind("</w:settings>"))
+        .context("Invalid settings part")?;
+    xml.insert_str(at, element);
+    package.set_part(SETTINGS_XML, xml.into_bytes());
+    Ok(())
+}
+
+/// The start of the first element with the given name.
+pub fn find_element(xml: &str, name: &str) -> Option<usize> {
+    let open = format!("<{}", name);
+    xml.match_indices(&open)
+        .map(|(i, _)| i)
+        .find(|&i| matches!(xml.as_bytes().get(i + open.len()), Some(b' ' | b'/' | b'>')))
+}
+
+/// Escape text for use in XML content or attribute values.
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The byte range of the `<w:p>` element containing `text`.
+pub fn find_paragraph(xml: &str, text: &str) -> Option<std::ops::Range<usize>> {
+    let at = xml.find(text)?;
+    let start = xml[..at]
+        .rmatch_indices("<w:p")
+        .find(|(i, _)| matches!(xml.as_bytes().get(i + 4), Some(b'>') | Some(b' ')))
+        .map(|(i, _)| i)?;
+    let end = at + xml[at..].find("</w:p>")? + "</w:p>".len();
+    Some(start..end)
+}
+
+/// The byte range of the body's final `<w:sectPr>` element.
+pub fn find_body_sect_pr(xml: &str) -> Option<std::ops::Range<usize>> {
+    let body_end = xml.rfind("</w:body>")?;
+    let start = xml[..body_end].rfind("<w:sectPr")?;
+    // a sectPr inside a paragraph belongs to that paragraph, not the body
+    if xml[start..body_end].contains("</w:p>") {
+        return None;
+    }
+    let end = element_end(xml, start, "w:sectPr")?;
+    Some(start..end)
+}
+
+/// The end of the element starting at `start`, which may be self-closing.
+pub fn element_end(xml: &str, start: usize, name: &str) -> Option<usize> {
+    let tag_end = start + xml[start..].find('>')? + 1;
+    if xml[..tag_end].ends_with("/>") {
+        return Some(tag_end);
+    }
+    let close = format!("</{}>", name);
+    Some(tag_end + xml[tag_end..].find(&close)? + close.len())
+}
+
+// set the w:type of a sectPr, respecting the schema order of its children
+fn set_section_type(sect_pr: &str, section_type: &str) -> String {
+    let mut sect_pr = sect_pr.to_string();
+    if let Some(start) = sect_pr.find("<w:type ") {
+        if let Some(end) = element_end(&sect_pr, start, "w:type") {
+            sect_pr.replace_range(start..end, "");
+        }
+    }
+    if sect_pr.ends_with("/>") {
+        let open = sect_pr.trim_end_matches("/>").trim_end().to_string();
+        sect_pr = format!("{}></w:sectPr>", open);
+    }
+    let element = format!(r#"<w:type w:val="{}"/>"#, section_type);
+    let at = [
+        "<w:pgSz",
+        "<w:pgMar",
+        "<w:paperSrc",
+        "<w:pgBorders",
+        "<w:lnNumType",
+        "<w:pgNumType",
+        "<w:cols",
+        "<w:formProt",
+        "<w:vAlign",
+        "<w:noEndnote",
+        "<w:titlePg",
+        "<w:textDirection",
+        "<w:bidi",
+        "<w:rtlGutter",
+        "<w:docGrid",
+        "<w:printerSettings",
+        "<w:sectPrChange",
+        "</w:sectPr>",
+    ]
+    .iter()
+    .filter_map(|tag| sect_pr.find(tag))
+    .min()
+    .unwrap_or(sect_pr.len());
+    sect_pr.insert_str(at, &element);
+    sect_pr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAMESPACES: &str =
+        r#"xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main""#;
+
+    fn package(body: &str) -> Package {
+        Package::from_parts(&[(
+            DOCUMENT_XML,
+            &format!(
+                "<w:document {}><w:body>{}</w:body></w:document>",
+                NAMESPACES, body
+            ),
+        )])
+    }
+
+    fn body(package: &Package) -> String {
+        let xml = package.xml(DOCUMENT_XML).unwrap();
+        let start = xml.find("<w:body>").unwrap() + "<w:body>".len();
+        xml[start..xml.rfind("</w:body>").unwrap()].to_string()
+    }
+
+    fn paragraph(text: &str) -> String {
+        format!("<w:p><w:r><w:t>{}</w:t></w:r></w:p>", text)
+    }
+
+    fn section_break(sect_pr: &str) -> String {
+        format!("<w:p><w:pPr>{}</w:pPr></w:p>", sect_pr)
+    }
+
+    const SECT_PR: &str = r#"<w:sectPr><w:pgSz w:w="100"/><w:pgMar w:top="1"/></w:sectPr>"#;
+
+    fn typed(section_type: &str) -> String {
+        format!(
+            r#"<w:sectPr><w:type w:val="{}"/><w:pgSz w:w="100"/><w:pgMar w:top="1"/></w:sectPr>"#,
+            section_type
+        )
+    }
+
+    #[test]
+    fn page_breaks() {
+        let (a, b) = (paragraph("A"), paragraph("B"));
+        let mut package = package(&format!(
+            "{}{}{}{}",
+            a,
+            paragraph(Break::Page.marker()),
+            b,
+            SECT_PR
+        ));
+        apply_breaks(&mut package).unwrap();
+        assert_eq!(
+            body(&package),
+            format!(
+                r#"{}<w:p><w:r><w:br w:type="page"/></w:r></w:p>{}{}"#,
+                a, b, SECT_PR
+            )
+        );
+    }
+
+    #[test]
+    fn sections_take_the_type_of_the_break_before_them() {
+        let (a, b, c) = (paragraph("A"), paragraph("B"), paragraph("C"));
+        let mut package = package(&format!(
+            "{}{}{}{}{}{}",
+            a,
+            paragraph(Break::SectionOddPage.marker()),
+            b,
+            paragraph(Break::SectionNextPage.marker()),
+            c,
+            SECT_PR
+        ));
+        apply_breaks(&mut package).unwrap();
+        assert_eq!(
+            body(&package),
+            format!(
+                "{}{}{}{}{}{}",
+                a,
+                section_break(SECT_PR),
+                b,
+                section_break(&typed("oddPage")),
+                c,
+                typed("nextPage")
+            )
+        );
+    }
+
+    #[test]
+    fn the_body_section_type_is_set_in_schema_order() {
+        let cases = [
+            // self-closing
+            (
+                "<w:sectPr/>",
+                r#"<w:sectPr><w:type w:val="oddPage"/></w:sectPr>"#,
+            ),
+            (
+                "<w:sectPr />",
+                r#"<w:sectPr><w:type w:val="oddPage"/></w:sectPr>"#,
+            ),
+            // with a type of its own
+            (
+                r#"<w:sectPr><w:headerReference w:type="default" r:id="rId1"/><w:type w:val="continuous"/><w:pgSz w:w="100"/></w:sectPr>"#,
+                r#"<w:sectPr><w:headerReference w:type="default" r:id="rId1"/><w:type w:val="oddPage"/><w:pgSz w:w="100"/></w:sectPr>"#,
+            ),
+            // with nothing the type goes before
+            (
+                r#"<w:sectPr><w:footnotePr/></w:sectPr>"#,
+                r#"<w:sectPr><w:footnotePr/><w:type w:val="oddPage"/></w:sectPr>"#,
+            ),
+        ];
+        for (sect_pr, expected) in cases {
+            assert_eq!(set_section_type(sect_pr, "oddPage"), expected);
+        }
+
+        // a body without a sectPr is given one
+        let a = paragraph("A");
+        let mut package = package(&format!(
+            "{}{}",
+            paragraph(Break::SectionOddPage.marker()),
+            a
+        ));
+        apply_breaks(&mut package).unwrap();
+        assert_eq!(
+            body(&package),
+            format!(
+                "{}{}{}",
+                section_break("<w:sectPr/>"),
+                a,
+                r#"<w:sectPr><w:type w:val="oddPage"/></w:sectPr>"#
+            )
+        );
+    }
+
+    // the settings part of pandoc's reference document
+    const PANDOC_SETTINGS: &str = concat!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#,
+        "\n",
+        r#"<w:settings xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main" xmlns:m="http://schemas.openxmlformats.org/officeDocument/2006/math">"#,
+        r#"<w:zoom w:percent="90" /><w:embedSystemFonts /><w:proofState w:spelling="clean" w:grammar="clean" />"#,
+        r#"<w:stylePaneFormatFilter w:val="0004" /><w:defaultTabStop w:val="720" />"#,
+        r#"<w:characterSpacingControl w:val="doNotCompress" />"#,
+        r#"<w:footnotePr><w:footnote w:id="-1" /><w:footnote w:id="0" /></w:footnotePr>"#,
+        r#"<w:endnotePr><w:endnote w:id="-1" /><w:endnote w:id="0" /></w:endnotePr>"#,
+        r#"<w:compat /><w:rsids><w:rsidRoot w:val="00590D07" /></w:rsids>"#,
+        r#"<m:mathPr><m:mathFont m:val="Cambria Math" /></m:mathPr>"#,
+        r#"<w:themeFontLang w:val="en-US" /><w:doNotAutoCompressPictures />"#,
+        r#"<w:decimalSymbol w:val="." /><w:listSeparator w:val="," />"#,
+        "</w:settings>"
+    );
+
+    #[test]
+    fn tables_of_contents_are_dirty_fields() {
+        let mut package = Package::from_parts(&[
+            (
+                DOCUMENT_XML,
+                &format!(
+                    "<w:document {}><w:body>{}{}</w:body></w:document>",
+                    NAMESPACES,
+                    paragraph(TOC_MARKER),
+                    paragraph(LIST_OF_FIGURES_MARKER)
+                ),
+            ),
+            (SETTINGS_XML, PANDOC_SETTINGS),
+        ]);
+        insert_toc(
+            &mut package,
+            TOC_MARKER,
+            "Contents & more",
+            r#"TOC \o "1-3" \h \z \u"#,
+        )
+        .unwrap();
+        insert_list(
+            &mut package,
+            LIST_OF_FIGURES_MARKER,
+            "Figures",
+            r#"TOC \h \z \c "Figure""#,
+        )
+        .unwrap();
+
+        let body = body(&package);
+        assert!(body.starts_with(concat!(
+            r#"<w:sdt><w:sdtPr><w:docPartObj><w:docPartGallery w:val="Table of Contents"/><w:docPartUnique/></w:docPartObj></w:sdtPr><w:sdtContent>"#,
+            r#"<w:p><w:pPr><w:pStyle w:val="TOCHeading"/></w:pPr><w:r><w:t xml:space="preserve">Contents &amp; more</w:t></w:r></w:p>"#,
+            r#"<w:p><w:r><w:fldChar w:fldCharType="begin" w:dirty="true"/></w:r>"#,
+            r#"<w:r><w:instrText xml:space="preserve"> TOC \o &quot;1-3&quot; \h \z \u </w:instrText></w:r>"#,
+        )));
+        assert!(body.ends_with(concat!(
+            r#"<w:r><w:instrText xml:space="preserve"> TOC \h \z \c &quot;Figure&quot; </w:instrText></w:r>"#,
+            r#"<w:r><w:fldChar w:fldCharType="separate"/></w:r>"#,
+            r#"<w:r><w:t>Update the field to show the figures.</w:t></w:r>"#,
+            r#"<w:r><w:fldChar w:fldCharType="end"/></w:r></w:p>"#,
+        )));
+        assert!(!body.contains("MDBOOK-DOCX"));
+
+        // once, ahead of the footnote properties as the schema orders them
+        assert_eq!(
+            package.xml(SETTINGS_XML).unwrap(),
+            PANDOC_SETTINGS.replace(
+                "<w:footnotePr>",
+                r#"<w:updateFields w:val="true"/><w:footnotePr>"#
+            )
+        );
+    }
+
+    #[test]
+    fn settings_are_added_in_schema_order() {
+        let settings =
+            |children: &str| format!("<w:settings {}>{}</w:settings>", NAMESPACES, children);
+        let cases = [
+            // after everything it follows
+            (
+                settings(r#"<w:zoom w:percent="90"/>"#),
+                settings(r#"<w:zoom w:percent="90"/><w:updateFields w:val="true"/>"#),
+            ),
+            (settings(""), settings(r#"<w:updateFields w:val="true"/>"#)),
+            // a setting already there is left as it is
+            (
+                settings(r#"<w:updateFields w:val="false"/><w:compat/>"#),
+                settings(r#"<w:updateFields w:val="false"/><w:compat/>"#),
+            ),
+            // ahead of the first setting that follows it
+            (
+                settings(
+                    r#"<w:defaultTabStop w:val="720"/><w:compat><w:compatSetting/></w:compat><w:rsids/>"#,
+                ),
+                settings(
+                    r#"<w:defaultTabStop w:val="720"/><w:updateFields w:val="true"/><w:compat><w:compatSetting/></w:compat><w:rsids/>"#,
+                ),
+            ),
+        ];
+        for (before, expected) in cases {
+            let mut package = Package::from_parts(&[(SETTINGS_XML, &before)]);
+            update_fields_on_open(&mut package).unwrap();
+            assert_eq!(package.xml(SETTINGS_XML).unwrap(), expected);
+        }
+
+        let mut package = Package::from_parts(&[(SETTINGS_XML, &settings(""))]);
+        assert!(add_setting(&mut package, "<w:unknown/>").is_err());
+    }
+
+    fn numbered(label: &str, number: u32) -> String {
+        format!(
+            concat!(
+                r#"<w:r><w:t xml:space="preserve">{} </w:t></w:r>"#,
+                r#"<w:r><w:fldChar w:fldCharType="begin"/></w:r>"#,
+                r#"<w:r><w:instrText xml:space="preserve"> SEQ {} \* ARABIC </w:instrText></w:r>"#,
+                r#"<w:r><w:fldChar w:fldCharType="separate"/></w:r>"#,
+                r#"<w:r><w:t>{}</w:t></w:r>"#,
+                r#"<w:r><w:fldChar w:fldCharType="end"/></w:r>"#,
+                r#"<w:r><w:t xml:space="preserve">: </w:t></w:r>"#
+            ),
+            label, label, number
+        )
+    }
+
+    #[test]
+    fn captions_are_numbered_per_style() {
+        let caption = |style: &str, text: &str| {
+            format!(
+                r#"<w:p><w:pPr><w:pStyle w:val="{}"/></w:pPr><w:r><w:t>{}</w:t></w:r></w:p>"#,
+                style, text
+            )
+        };
+        // a caption pandoc gave more properties than its style
+        let centred = r#"<w:p><w:pPr><w:pStyle w:val="ImageCaption"/><w:jc w:val="center"/><w:keepNext/></w:pPr><w:r><w:t>c</w:t></w:r></w:p>"#;
+        let seq = r#"<w:p><w:pPr><w:pStyle w:val="ImageCaption"/></w:pPr><w:r><w:instrText> SEQ Figure \* ARABIC </w:instrText></w:r></w:p>"#;
+        let mut package = package(&format!(
+            "{}{}{}{}{}{}",
+            caption("ImageCaption", "a"),
+            caption("TableCaption", "t"),
+            seq,
+            caption("ImageCaptionWide", "w"),
+            centred,
+            caption("ImageCaption", "d"),
+        ));
+        number_captions(&mut package, "ImageCaption", "Figure").unwrap();
+        number_captions(&mut package, "TableCaption", "Table").unwrap();
+
+        assert_eq!(
+            body(&package),
+            format!(
+                concat!(
+                    r#"<w:p><w:pPr><w:pStyle w:val="ImageCaption"/></w:pPr>{}<w:r><w:t>a</w:t></w:r></w:p>"#,
+                    r#"<w:p><w:pPr><w:pStyle w:val="TableCaption"/></w:pPr>{}<w:r><w:t>t</w:t></w:r></w:p>"#,
+                    "{}{}",
+                    r#"<w:p><w:pPr><w:pStyle w:val="ImageCaption"/><w:jc w:val="center"/><w:keepNext/></w:pPr>{}<w:r><w:t>c</w:t></w:r></w:p>"#,
+                    r#"<w:p><w:pPr><w:pStyle w:val="ImageCaption"/></w:pPr>{}<w:r><w:t>d</w:t></w:r></w:p>"#,
+                ),
+                numbered("Figure", 1),
+                numbered("Table", 1),
+                seq,
+                caption("ImageCaptionWide", "w"),
+                numbered("Figure", 2),
+                numbered("Figure", 3),
+            )
+        );
+    }
+
+    fn styled(style: &str, text: &str) -> String {
+        format!(
+            r#"<w:p><w:pPr><w:pStyle w:val="{}"/></w:pPr><w:r><w:t>{}</w:t></w:r></w:p>"#,
+            style, text
+        )
+    }
+
+    #[test]
+    fn title_block_is_removed() {
+        let heading = styled("Heading1", "Intro");
+        let title_block = format!(
+            "{}{}{}{}{}",
+            styled("Title", "Book"),
+            styled("Subtitle", "Sub"),
+            styled("Author", "A"),
+            styled("Author", "B"),
+            styled("Date", "Today"),
+        );
+        let all = ["Title", "Subtitle", "Author", "Author", "Date"];
+        // a first chapter that starts with paragraphs in title block styles
+        let chapter = format!(
+            "{}{}{}",
+            styled("Author", "By C"),
+            styled("Date", "Then"),
+            heading
+        );
+        let cases: [(String, &[&str], bool, String); 6] = [
+            // none
+            (heading.clone(), &[], false, heading.clone()),
+            (heading.clone(), &all, false, heading.clone()),
+            // a full title block
+            (
+                format!("{}{}", title_block, heading),
+                &all,
+                false,
+                heading.clone(),
+            ),
+            // a title taken from the content
+            (
+                format!("{}{}", title_block, heading),
+                &all,
+                true,
+                format!("{}{}", styled("Title", "Book"), heading),
+            ),
+            // fields left out by pandoc, before the first chapter
+            (
+                format!("{}{}", styled("Title", "Book"), chapter),
+                &["Title", "Date"],
+                false,
+                chapter.clone(),
+            ),
+            (
+                format!(
+                    "{}{}{}",
+                    styled("Title", "Book"),
+                    styled("Author", "A"),
+                    chapter
+                ),
+                &["Title", "Author"],
+                false,
+                chapter.clone(),
+            ),
+        ];
+        for (before, styles, keep_title, expected) in cases {
+            let mut package = package(&before);
+            remove_title_block(&mut package, styles, keep_title).unwrap();
+            assert_eq!(
+                body(&package),
+                expected,
+                "removing {:?} from {}",
+                styles,
+                before
+            );
+        }
+    }
+}
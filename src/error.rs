@@ -0,0 +1,76 @@
+//! Errors raised while creating documents.
+
+use crate::validate::ValidationError;
+use pandoc::PandocError;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// An error that stops the renderer.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// mdBook's render context could not be read
+    #[error("Error with the input data. Is everything formatted correctly?\n{0}")]
+    Input(String),
+    /// the `output.docx` table of book.toml could not be deserialized
+    #[error("Error reading \"output.docx\" configuration in book.toml. Check that all values are of the correct data type.\n{0}")]
+    Config(String),
+    /// a document could not be created
+    #[error("Failed to create {}: {source}", .document.display())]
+    Document {
+        document: PathBuf,
+        #[source]
+        source: DocumentError,
+    },
+    /// one or more documents failed under `on_error = "continue"`
+    #[error("{failed} of {total} documents failed.")]
+    Failed { failed: usize, total: usize },
+}
+
+/// An error raised while creating a single document.
+#[derive(Debug, Error)]
+pub enum DocumentError {
+    /// a document option holds an invalid value
+    #[error("invalid configuration. {0}")]
+    Config(String),
+    /// the selectors did not match any chapter
+    #[error("No markdown files match the specified include and/or exclude filters. Verify your filenames and filters are correct.")]
+    NoChapters,
+    /// links to chapters outside the document under `unresolved_links = "error"`
+    #[error("unresolved links:\n{}", .0.join("\n"))]
+    UnresolvedLinks(Vec<String>),
+    /// problems found by the validation stage in strict mode
+    #[error(transparent)]
+    Validation(#[from] ValidationError),
+    /// a file placed into the document could not be read
+    #[error("unable to read {}: {source}", .path.display())]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    /// the directory a document is written to could not be created
+    #[error("unable to create {}: {source}", .path.display())]
+    Write {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    /// pandoc could not be run or reported an error
+    #[error("pandoc failed. {0}")]
+    Pandoc(String),
+    /// the .docx produced by pandoc could not be post-processed
+    #[error("post-processing failed. {0:#}")]
+    PostProcess(anyhow::Error),
+}
+
+impl From<PandocError> for DocumentError {
+    fn from(e: PandocError) -> Self {
+        DocumentError::Pandoc(match e {
+            PandocError::Err(output) => String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            PandocError::PandocNotFound => {
+                "Pandoc not found, did you forget to install pandoc?".to_string()
+            }
+            e => e.to_string(),
+        })
+    }
+}
@@ -0,0 +1,142 @@
+//! Extra files placed into a document around its chapters.
+//!
+//! Markdown inserts join the chapters in the markdown handed to pandoc.
+//! Every other kind is held in its place by a marker paragraph, which is
+//! swapped for the file's content once pandoc has produced the .docx.
+
+use serde_derive::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// A file placed into the document at a fixed position.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Insert {
+    /// the file, relative to book.toml
+    pub path: PathBuf,
+    pub kind: Kind,
+    pub position: Position,
+}
+
+/// How an inserted file is read.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kind {
+    /// a Word document merged in as its own section
+    Docx,
+    /// markdown converted along with the chapters
+    Markdown,
+    /// WordprocessingML body content, such as `<w:p>` and `<w:tbl>` elements,
+    /// copied into the document as it is
+    RawOpenxml,
+}
+
+impl Kind {
+    /// The kind of a `prepend` or `append` file, from its extension.
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("md") => Kind::Markdown,
+            _ => Kind::Docx,
+        }
+    }
+}
+
+/// Where an inserted file is placed.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "PositionValue")]
+pub enum Position {
+    /// at the start of the document
+    Before,
+    /// at the end of the document
+    After,
+    /// after the table of contents, ahead of the first chapter
+    AfterToc,
+    /// ahead of the chapter with this path, relative to the src dir
+    BeforeChapter(PathBuf),
+}
+
+// the toml crate only reads enums from strings, so the table form
+// `{ before-chapter = "x.md" }` is read separately
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PositionValue {
+    Name(String),
+    BeforeChapter {
+        #[serde(rename = "before-chapter")]
+        before_chapter: PathBuf,
+    },
+}
+
+impl TryFrom<PositionValue> for Position {
+    type Error = String;
+
+    fn try_from(value: PositionValue) -> Result<Self, Self::Error> {
+        match value {
+            PositionValue::BeforeChapter { before_chapter } => {
+                Ok(Position::BeforeChapter(before_chapter))
+            }
+            PositionValue::Name(name) => match name.as_str() {
+                "before" => Ok(Position::Before),
+                "after" => Ok(Position::After),
+                "after-toc" => Ok(Position::AfterToc),
+                _ => Err(format!(
+                    "unknown position \"{}\", expected \"before\", \"after\", \"after-toc\" or {{ before-chapter = \"<chapter>\" }}",
+                    name
+                )),
+            },
+        }
+    }
+}
+
+impl Insert {
+    pub fn new(path: PathBuf, kind: Kind, position: Position) -> Self {
+        Self {
+            path,
+            kind,
+            position,
+        }
+    }
+
+    /// The text of the paragraph holding the place of the insert with this
+    /// index until the .docx is post-processed.
+    pub fn marker(index: usize) -> String {
+        format!("MDBOOK-DOCX-INSERT-{}-HERE", index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(position: &str) -> Result<Insert, toml::de::Error> {
+        toml::from_str(&format!(
+            "path = \"legal.docx\"\nkind = \"docx\"\nposition = {}",
+            position
+        ))
+    }
+
+    #[test]
+    fn positions() {
+        let cases = [
+            (r#""before""#, Position::Before),
+            (r#""after""#, Position::After),
+            (r#""after-toc""#, Position::AfterToc),
+            (
+                r#"{ before-chapter = "ops/deploy.md" }"#,
+                Position::BeforeChapter(PathBuf::from("ops/deploy.md")),
+            ),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parse(value).unwrap().position, expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn unknown_positions_are_rejected() {
+        let error = parse(r#""after-chapter""#).unwrap_err().to_string();
+        assert!(
+            error.contains(r#"unknown position "after-chapter", expected "before", "after", "after-toc" or { before-chapter = "<chapter>" }"#),
+            "{}",
+            error
+        );
+        assert!(parse(r#"{ after-chapter = "x.md" }"#).is_err());
+    }
+}
@@ -0,0 +1,382 @@
+//! Rewriting of links between chapters into links to bookmarks inside the document.
+
+use crate::markdown::{self, Rewrite};
+use log::{debug, warn};
+use mdbook::book::Chapter;
+use serde_derive::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Component, Path, PathBuf},
+};
+
+/// How links to chapters that are not part of the document are handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnresolvedLinks {
+    /// keep the link, pointing it at `site_url` when one is set
+    #[default]
+    External,
+    /// drop the link and keep its text
+    Text,
+    /// fail the build
+    Error,
+}
+
+// the pandoc identifiers given to the headings of one chapter
+struct ChapterBookmarks {
+    // identifier of the inserted chapter title, if there is one
+    title: Option<String>,
+    // mdBook's anchor for each heading of the chapter file and its identifier
+    headings: Vec<(String, String)>,
+}
+
+impl ChapterBookmarks {
+    // the identifier of the heading the chapter starts with
+    fn start(&self) -> Option<&str> {
+        self.title
+            .as_deref()
+            .or_else(|| self.headings.first().map(|(_, id)| id.as_str()))
+    }
+}
+
+/// Maps each chapter of the document, and the anchors of its headings, to
+/// the pandoc identifiers that become bookmarks in the .docx.
+pub struct Bookmarks {
+    chapters: Vec<ChapterBookmarks>,
+    // chapter path, and source path, to its index in `chapters`
+    paths: HashMap<PathBuf, usize>,
+    // every chapter path in the book, selected or not
+    book: HashSet<PathBuf>,
+    // the directory chapter paths are relative to
+    src_dir: PathBuf,
+}
+
+impl Bookmarks {
+    /// Build the bookmarks for the selected chapters, each paired with
+    /// whether its SUMMARY.md title is inserted above its content, and for
+    /// the markdown inserts, each with its path relative to the src dir.
+    pub fn new<'a>(
+        src_dir: &Path,
+        selected: impl Iterator<Item = (&'a Chapter, bool)>,
+        inserts: impl Iterator<Item = (&'a Path, &'a str)>,
+        book: impl Iterator<Item = &'a Chapter>,
+    ) -> Self {
+        let mut chapters = Vec::new();
+        let mut paths = HashMap::new();
+        for (i, (ch, with_title)) in selected.enumerate() {
+            let headings = markdown::heading_anchors(&ch.content)
+                .into_iter()
+                .enumerate()
+                .map(|(j, anchor)| (anchor, format!("_ch{}-h{}", i + 1, j + 1)))
+                .collect();
+            chapters.push(ChapterBookmarks {
+                title: with_title.then(|| format!("_ch{}", i + 1)),
+                headings,
+            });
+            for path in [&ch.path, &ch.source_path].into_iter().flatten() {
+                paths.insert(path.clone(), i);
+            }
+        }
+        for (i, (path, content)) in inserts.enumerate() {
+            let headings = markdown::heading_anchors(content)
+                .into_iter()
+                .enumerate()
+                .map(|(j, anchor)| (anchor, format!("_ins{}-h{}", i + 1, j + 1)))
+                .collect();
+            // a chapter that is also inserted keeps its chapter bookmarks
+            if !paths.contains_key(path) {
+                paths.insert(path.to_path_buf(), chapters.len());
+                chapters.push(ChapterBookmarks {
+                    title: None,
+                    headings,
+                });
+            }
+        }
+        let book = book
+            .flat_map(|ch| [ch.path.clone(), ch.source_path.clone()])
+            .flatten()
+            .collect();
+        Self {
+            chapters,
+            paths,
+            book,
+            src_dir: src_dir.to_path_buf(),
+        }
+    }
+
+    /// The identifiers for the headings of a chapter's content, in order,
+    /// including the inserted title if there is one.
+    pub fn heading_ids(&self, chapter: &Path) -> Vec<String> {
+        self.paths
+            .get(chapter)
+            .map(|&i| {
+                let ch = &self.chapters[i];
+                ch.title
+                    .iter()
+                    .cloned()
+                    .chain(ch.headings.iter().map(|(_, id)| id.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Rewrite the links in the content of the chapter at `chapter` so links
+    /// to chapters in the document point at their bookmarks. Links that
+    /// cannot be resolved under `UnresolvedLinks::Error` are returned.
+    pub fn rewrite_links(
+        &self,
+        content: &str,
+        chapter: &Path,
+        unresolved: UnresolvedLinks,
+        site_url: Option<&str>,
+    ) -> (String, Vec<String>) {
+        let mut errors = Vec::new();
+        let content = markdown::rewrite_links(content, |dest| {
+            let Some((target, fragment)) = link_target(&self.src_dir, chapter, dest) else {
+                return Rewrite::Keep;
+            };
+            if let Some(id) = self.resolve(&target, fragment, chapter, dest) {
+                return Rewrite::Destination(format!("#{}", id));
+            }
+            debug!(
+                "{}: link to \"{}\" is outside the document",
+                chapter.display(),
+                dest
+            );
+            match unresolved {
+                UnresolvedLinks::External => match site_url {
+                    Some(base) if self.book.contains(&target) => {
+                        Rewrite::Destination(site_link(base, &target, fragment))
+                    }
+                    _ => Rewrite::Keep,
+                },
+                UnresolvedLinks::Text => Rewrite::Text,
+                UnresolvedLinks::Error => {
+                    errors.push(format!(
+                        "{}: link to \"{}\" is not part of this document",
+                        chapter.display(),
+                        dest
+                    ));
+                    Rewrite::Keep
+                }
+            }
+        });
+        (content, errors)
+    }
+
+    // the identifier a link to `target`, relative to the src dir, should point at
+    fn resolve(
+        &self,
+        target: &Path,
+        fragment: Option<&str>,
+        chapter: &Path,
+        dest: &str,
+    ) -> Option<&str> {
+        let ch = &self.chapters[*self.paths.get(target)?];
+        if let Some(fragment) = fragment {
+            match ch.headings.iter().find(|(anchor, _)| anchor == fragment) {
+                Some((_, id)) => return Some(id),
+                None => warn!(
+                    "{}: no heading \"#{}\" found for link \"{}\", linking to the start of the chapter.",
+                    chapter.display(),
+                    fragment,
+                    dest
+                ),
+            }
+        }
+        ch.start()
+    }
+}
+
+// the chapter path, relative to the src dir, and fragment a link points at.
+// Only relative links to .md or .html files, or to a fragment in the same
+// chapter, are considered.
+fn link_target<'a>(
+    src_dir: &Path,
+    chapter: &Path,
+    dest: &'a str,
+) -> Option<(PathBuf, Option<&'a str>)> {
+    let (path, fragment) = match dest.split_once('#') {
+        Some((path, fragment)) => (path, Some(fragment)),
+        None => (dest, None),
+    };
+    if path.is_empty() {
+        return fragment.map(|f| (chapter.to_path_buf(), Some(f)));
+    }
+    let has_scheme = path
+        .split_once(':')
+        .is_some_and(|(scheme, _)| !scheme.contains('/'));
+    if has_scheme || path.starts_with('/') {
+        return None;
+    }
+    let path = if let Some(stem) = path.strip_suffix(".html") {
+        format!("{}.md", stem)
+    } else if path.ends_with(".md") {
+        path.to_string()
+    } else {
+        return None;
+    };
+    // a file outside the src dir, such as an insert, reaches back into it
+    // through `..`, so the link is resolved against the src dir itself
+    let joined = src_dir
+        .join(chapter.parent().unwrap_or_else(|| Path::new("")))
+        .join(path);
+    let target = relative_to(src_dir, &joined).unwrap_or_else(|| normalize(&joined));
+    Some((target, fragment))
+}
+
+// resolve `.` and `..` components without touching the filesystem. Leading
+// `..` components of a relative path are kept.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match out.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    out.pop();
+                }
+                Some(Component::RootDir | Component::Prefix(_)) => {}
+                _ => out.push(".."),
+            },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// The path of `path` relative to `base`, through `..` where it sits outside
+/// it. `None` when the two share no root.
+pub fn relative_to(base: &Path, path: &Path) -> Option<PathBuf> {
+    let path = normalize(path);
+    let mut relative = PathBuf::new();
+    for ancestor in normalize(base).ancestors() {
+        if let Ok(rest) = path.strip_prefix(ancestor) {
+            relative.push(rest);
+            return Some(relative);
+        }
+        relative.push("..");
+    }
+    None
+}
+
+// the address of a chapter on the published web book
+fn site_link(base: &str, target: &Path, fragment: Option<&str>) -> String {
+    let page = target.with_extension("html");
+    let page = page.to_string_lossy().replace('\\', "/");
+    let page = match page.strip_suffix("README.html") {
+        Some(dir) => format!("{}index.html", dir),
+        None => page,
+    };
+    match fragment {
+        Some(fragment) => format!("{}/{}#{}", base.trim_end_matches('/'), page, fragment),
+        None => format!("{}/{}", base.trim_end_matches('/'), page),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chapter(path: &str, content: &str) -> Chapter {
+        Chapter::new("Chapter", content.to_string(), path, Vec::new())
+    }
+
+    #[test]
+    fn parent_components_are_kept_outside_the_root() {
+        assert_eq!(normalize(Path::new("a/./b/../c")), Path::new("a/c"));
+        assert_eq!(normalize(Path::new("../a/../../b")), Path::new("../../b"));
+        assert_eq!(normalize(Path::new("/a/../../b")), Path::new("/b"));
+        assert_eq!(
+            relative_to(Path::new("/book/src"), Path::new("/book/glossary.md")),
+            Some(PathBuf::from("../glossary.md"))
+        );
+        assert_eq!(
+            relative_to(Path::new("/book/src"), Path::new("/book/src/../src/a.md")),
+            Some(PathBuf::from("a.md"))
+        );
+    }
+
+    #[test]
+    fn chapter_links() {
+        let setup = chapter("ops/setup.md", "# Setup\n\n## Proxy settings\n");
+        let intro = chapter("intro.md", "# Intro\n");
+        let bookmarks = Bookmarks::new(
+            Path::new("/book/src"),
+            [(&intro, true), (&setup, false)].into_iter(),
+            std::iter::empty(),
+            [&intro, &setup].into_iter(),
+        );
+        let (content, errors) = bookmarks.rewrite_links(
+            "[a](../intro.md) [b](setup.md#proxy-settings) [c](./setup.html) [d](#setup)",
+            Path::new("ops/setup.md"),
+            UnresolvedLinks::Error,
+            None,
+        );
+        assert_eq!(
+            content,
+            "[a](#_ch1) [b](#_ch2-h2) [c](#_ch2-h1) [d](#_ch2-h1)"
+        );
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn insert_outside_the_src_dir_links_to_chapters_and_itself() {
+        let setup = chapter("ops/setup.md", "# Setup\n");
+        let glossary = "# Glossary\n\n## Term\n";
+        let bookmarks = Bookmarks::new(
+            Path::new("/book/src"),
+            [(&setup, false)].into_iter(),
+            [(Path::new("../glossary.md"), glossary)].into_iter(),
+            [&setup].into_iter(),
+        );
+        assert_eq!(
+            bookmarks.heading_ids(Path::new("../glossary.md")),
+            ["_ins1-h1", "_ins1-h2"]
+        );
+        let (content, errors) = bookmarks.rewrite_links(
+            "[setup](src/ops/setup.md) [self](glossary.md#glossary) [term](#term)",
+            Path::new("../glossary.md"),
+            UnresolvedLinks::Error,
+            None,
+        );
+        assert_eq!(
+            content,
+            "[setup](#_ch1-h1) [self](#_ins1-h1) [term](#_ins1-h2)"
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[test]
+    fn unresolved_links() {
+        let intro = chapter("intro.md", "# Intro\n");
+        let other = chapter("other.md", "# Other\n");
+        let bookmarks = Bookmarks::new(
+            Path::new("/book/src"),
+            [(&intro, false)].into_iter(),
+            std::iter::empty(),
+            [&intro, &other].into_iter(),
+        );
+        let rewrite = |unresolved, site_url| {
+            bookmarks.rewrite_links(
+                "[other](other.md#x) [web](https://x.org/a.md)",
+                Path::new("intro.md"),
+                unresolved,
+                site_url,
+            )
+        };
+        assert_eq!(
+            rewrite(UnresolvedLinks::External, Some("https://book.org/")).0,
+            "[other](https://book.org/other.html#x) [web](https://x.org/a.md)"
+        );
+        assert_eq!(
+            rewrite(UnresolvedLinks::Text, None).0,
+            "other [web](https://x.org/a.md)"
+        );
+        let (_, errors) = rewrite(UnresolvedLinks::Error, None);
+        assert_eq!(
+            errors,
+            ["intro.md: link to \"other.md#x\" is not part of this document"]
+        );
+    }
+}
@@ -0,0 +1,94 @@
+//! Logger setup, with support for grouping the output of each document
+//! when several are built at once.
+
+use chrono::Local;
+use env_logger::Builder;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::{
+    cell::RefCell,
+    env,
+    io::{self, Write},
+};
+
+thread_local! {
+    // log lines held back while a document is built on this thread
+    static BUFFER: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+fn format_record(record: &Record) -> String {
+    format!(
+        "{} [{}] ({}): {}",
+        Local::now().format("%Y-%m-%d %H:%M:%S"),
+        record.level(),
+        record.target(),
+        record.args()
+    )
+}
+
+// env_logger's filtering and output, plus buffering for grouped output
+struct GroupedLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for GroupedLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        let buffered = BUFFER.with(|buffer| match buffer.borrow_mut().as_mut() {
+            Some(lines) => {
+                lines.push(format_record(record));
+                true
+            }
+            None => false,
+        });
+        if !buffered {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+pub fn init_logger() {
+    let mut builder = Builder::new();
+
+    builder.format(|formatter, record| writeln!(formatter, "{}", format_record(record)));
+
+    if let Ok(var) = env::var("RUST_LOG") {
+        builder.parse_filters(&var);
+    } else {
+        // if no RUST_LOG provided, default to logging at the Info level
+        builder.filter(None, LevelFilter::Info);
+        // Filter extraneous html5ever not-implemented messages
+        builder.filter(Some("html5ever"), LevelFilter::Error);
+    }
+
+    let inner = builder.build();
+    log::set_max_level(inner.filter());
+    log::set_boxed_logger(Box::new(GroupedLogger { inner }))
+        .expect("The logger is only initialised once.");
+}
+
+/// Run `f`, holding back everything it logs on this thread and writing it
+/// out in one block once it returns, so concurrent output is not interleaved.
+pub fn grouped<T>(f: impl FnOnce() -> T) -> T {
+    BUFFER.with(|buffer| *buffer.borrow_mut() = Some(Vec::new()));
+    let result = f();
+    let lines = BUFFER
+        .with(|buffer| buffer.borrow_mut().take())
+        .unwrap_or_default();
+    if !lines.is_empty() {
+        let mut stderr = io::stderr().lock();
+        for line in lines {
+            let _ = writeln!(stderr, "{}", line);
+        }
+    }
+    result
+}
@@ -1,71 +1,264 @@
-use anyhow::{bail, Result};
-use chrono::Local;
-use env_logger::Builder;
+mod cache;
+mod diagram;
+mod docx;
+mod error;
+mod insert;
+mod links;
+mod logging;
+mod markdown;
+mod merge;
+mod svg;
+mod template;
+mod validate;
+
+use anyhow::Context;
+use error::{DocumentError, Error};
 use glob::Pattern;
-use log::{error, LevelFilter};
+use insert::{Insert, Position};
+use log::{debug, error, info, warn};
+use mdbook::book::Chapter;
 use mdbook::renderer::RenderContext;
 use mdbook::BookItem;
-use pandoc::{OutputKind, MarkdownExtension, Pandoc, PandocOption};
+use pandoc::{MarkdownExtension, OutputKind, Pandoc, PandocOption};
 use serde_derive::Deserialize;
 use std::{
-    env,
-    io::{self, Write},
-    path::PathBuf,
+    collections::BTreeMap,
+    fmt, fs, io,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-fn init_logger() {
-    let mut builder = Builder::new();
-
-    builder.format(|formatter, record| {
-        writeln!(
-            formatter,
-            "{} [{}] ({}): {}",
-            Local::now().format("%Y-%m-%d %H:%M:%S"),
-            record.level(),
-            record.target(),
-            record.args()
-        )
-    });
-
-    if let Ok(var) = env::var("RUST_LOG") {
-        builder.parse_filters(&var);
-    } else {
-        // if no RUST_LOG provided, default to logging at the Info level
-        builder.filter(None, LevelFilter::Info);
-        // Filter extraneous html5ever not-implemented messages
-        builder.filter(Some("html5ever"), LevelFilter::Error);
-    }
-
-    builder.init();
+/// What happens to the remaining documents when one fails.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnError {
+    /// stop at the first failing document
+    #[default]
+    Stop,
+    /// attempt every document and report the failures at the end
+    Continue,
 }
 
-#[derive(Debug, Deserialize)]
+// how long a document took to build and whether it succeeded
+type Outcome = (Duration, Result<(), DocumentError>);
+
+#[derive(Debug, Default, Deserialize)]
 pub struct DocumentList {
     #[serde(default)]
     documents: Vec<Document>,
-}
-
-impl Default for DocumentList {
-    fn default() -> Self {
-        Self { documents: vec![] }
-    }
+    #[serde(default)]
+    on_error: OnError,
+    #[serde(default)]
+    jobs: Option<usize>,
+    #[serde(default)]
+    force: bool,
+    #[serde(default)]
+    version: Option<String>,
 }
 
 impl DocumentList {
-    fn process(self, context: RenderContext) -> Result<()> {
-        for doc in self.documents {
-            let result = doc.process(context.clone());
-            // if this itteration has an error, return the error
-            // else loop continues
-            if let Err(e) = result {
-                bail!(e)
+    fn process(self, context: RenderContext) -> Result<(), Error> {
+        let total = self.documents.len();
+        // documents are independent of each other, so they're built
+        // concurrently by a pool of `jobs` worker threads
+        let jobs = self
+            .jobs
+            .filter(|&jobs| jobs > 0)
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+            .min(total.max(1));
+        // placeholders are expanded up front so the summary and the cache
+        // use the final filenames; a document that fails to expand is
+        // reported like any other failure when its turn comes
+        let values = template::Values::new(&context.root, self.version.as_deref());
+        let (filenames, documents): (Vec<PathBuf>, Vec<Result<Document, DocumentError>>) = self
+            .documents
+            .into_iter()
+            .map(|d| {
+                let filename = d.filename.clone();
+                let expanded = d.expand(&values);
+                match expanded {
+                    Ok(d) => (d.filename.clone(), Ok(d)),
+                    Err(e) => (filename, Err(e)),
+                }
+            })
+            .unzip();
+        let queue = Mutex::new(documents.into_iter().enumerate());
+        let results: Mutex<Vec<Option<Outcome>>> = Mutex::new((0..total).map(|_| None).collect());
+        let stop = AtomicBool::new(false);
+        let cache = cache::Cache::load(&context.destination, self.force);
+
+        let worker = || loop {
+            if stop.load(Ordering::SeqCst) {
+                break;
+            }
+            let Some((i, doc)) = queue.lock().unwrap().next() else {
+                break;
+            };
+            let started = Instant::now();
+            let result = match doc {
+                // keep each document's log output together
+                Ok(doc) if jobs > 1 => logging::grouped(|| doc.process(context.clone(), &cache)),
+                Ok(doc) => doc.process(context.clone(), &cache),
+                Err(e) => Err(e),
+            };
+            // if this itteration has an error, stop handing out documents
+            // unless we've been asked to carry on with the remaining ones
+            if result.is_err() && self.on_error == OnError::Stop {
+                stop.store(true, Ordering::SeqCst);
+            }
+            results.lock().unwrap()[i] = Some((started.elapsed(), result));
+        };
+        thread::scope(|scope| {
+            for _ in 0..jobs {
+                scope.spawn(worker);
+            }
+        });
+        cache.save();
+
+        let mut summary: Vec<(PathBuf, Duration, bool)> = Vec::new();
+        for (document, result) in filenames.into_iter().zip(results.into_inner().unwrap()) {
+            let Some((elapsed, result)) = result else {
+                continue;
             };
+            match result {
+                Ok(()) => summary.push((document, elapsed, true)),
+                Err(source) => {
+                    let e = Error::Document {
+                        document: document.clone(),
+                        source,
+                    };
+                    if self.on_error == OnError::Stop {
+                        return Err(e);
+                    }
+                    error!("{}", e);
+                    summary.push((document, elapsed, false));
+                }
+            }
         }
 
+        if self.on_error == OnError::Continue {
+            log_summary(&summary);
+            let failed = summary.iter().filter(|(_, _, ok)| !ok).count();
+            if failed > 0 {
+                return Err(Error::Failed { failed, total });
+            }
+        }
         Ok(())
     }
 }
 
+// log a table of every document attempted, its outcome and how long it took
+fn log_summary(results: &[(PathBuf, Duration, bool)]) {
+    let width = results
+        .iter()
+        .map(|(document, _, _)| document.display().to_string().len())
+        .chain(["Document".len()])
+        .max()
+        .unwrap_or_default();
+    let mut table = format!("{:<width$}  {:<6}  {:>8}", "Document", "Result", "Duration");
+    for (document, elapsed, ok) in results {
+        table.push_str(&format!(
+            "\n{:<width$}  {:<6}  {:>7.2}s",
+            document.display().to_string(),
+            if *ok { "ok" } else { "FAILED" },
+            elapsed.as_secs_f64()
+        ));
+    }
+    info!("Summary of documents created:\n{}", table);
+}
+
+/// The order chapters are concatenated in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChapterOrder {
+    /// the order chapters appear in SUMMARY.md
+    #[default]
+    Summary,
+    /// the order of the first `include` pattern each chapter matches,
+    /// with glob matches sorted by their SUMMARY.md position
+    Include,
+}
+
+/// How mdBook draft chapters, those without a file, are handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DraftMode {
+    /// leave draft chapters out of the document
+    #[default]
+    Skip,
+    /// insert the chapter title as a heading followed by the `draft_placeholder` text
+    Placeholder,
+}
+
+/// How chapter headings are levelled relative to each other.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeadingLevels {
+    /// every chapter keeps the heading levels written in its file
+    #[default]
+    Flat,
+    /// each chapter's headings are shifted down by its depth in SUMMARY.md
+    Nesting,
+}
+
+/// When a chapter's SUMMARY.md title is inserted as a heading above its content.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChapterTitles {
+    /// only when the chapter file does not start with a heading
+    Auto,
+    /// before every chapter
+    Always,
+    /// never, the chapter content is used as is
+    #[default]
+    Never,
+}
+
+/// The break inserted between consecutive chapters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChapterBreak {
+    /// chapters run straight into each other
+    #[default]
+    None,
+    /// a page break
+    Page,
+    /// a section break starting the next chapter on the next page
+    SectionNextPage,
+    /// a section break starting the next chapter on the next odd page
+    SectionOddPage,
+}
+
+/// How SVG images are placed in the document.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SvgMode {
+    /// hand the SVG to pandoc as it is
+    Keep,
+    /// convert the SVG to a PNG
+    #[default]
+    Png,
+    /// convert the SVG to a PNG and embed the SVG too, for Word versions
+    /// that can show it
+    PngAndSvg,
+}
+
+impl ChapterBreak {
+    fn as_break(self) -> Option<docx::Break> {
+        match self {
+            ChapterBreak::None => None,
+            ChapterBreak::Page => Some(docx::Break::Page),
+            ChapterBreak::SectionNextPage => Some(docx::Break::SectionNextPage),
+            ChapterBreak::SectionOddPage => Some(docx::Break::SectionOddPage),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Document {
     #[serde(default)]
@@ -75,11 +268,71 @@ pub struct Document {
     #[serde(default)]
     pub include: Option<Vec<PathBuf>>,
     #[serde(default)]
+    pub exclude: Vec<PathBuf>,
+    #[serde(default)]
+    pub sections: Vec<String>,
+    #[serde(default)]
+    pub parts: Vec<String>,
+    #[serde(default)]
+    pub order: ChapterOrder,
+    #[serde(default)]
+    pub drafts: DraftMode,
+    #[serde(default = "default_draft_placeholder")]
+    pub draft_placeholder: String,
+    #[serde(default)]
+    pub heading_levels: HeadingLevels,
+    #[serde(default)]
+    pub chapter_titles: ChapterTitles,
+    #[serde(default)]
+    pub chapter_break: ChapterBreak,
+    #[serde(default)]
+    pub unresolved_links: links::UnresolvedLinks,
+    #[serde(default)]
+    pub site_url: Option<String>,
+    #[serde(default)]
+    pub strict: bool,
+    #[serde(default)]
+    pub toc: bool,
+    #[serde(default = "default_toc_depth")]
+    pub toc_depth: u32,
+    #[serde(default = "default_toc_title")]
+    pub toc_title: String,
+    #[serde(default)]
+    pub list_of_figures: bool,
+    #[serde(default)]
+    pub list_of_tables: bool,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub subtitle: Option<String>,
+    #[serde(default)]
+    pub author: Option<Vec<String>>,
+    #[serde(default)]
+    pub date: Option<String>,
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    #[serde(default)]
+    pub subject: Option<String>,
+    #[serde(default)]
+    pub properties: BTreeMap<String, String>,
+    #[serde(default)]
+    pub title_block: bool,
+    #[serde(default)]
+    pub resource_paths: Vec<PathBuf>,
+    #[serde(default)]
+    pub svg: SvgMode,
+    #[serde(default = "default_svg_dpi")]
+    pub svg_dpi: u32,
+    #[serde(default = "default_diagrams")]
+    pub diagrams: BTreeMap<String, String>,
+    #[serde(default)]
     pub offset_headings_by: Option<i32>,
     #[serde(default)]
     pub append: Option<Vec<PathBuf>>,
     #[serde(default)]
     pub prepend: Option<Vec<PathBuf>>,
+    #[serde(default)]
+    pub insert: Vec<Insert>,
 }
 
 impl Default for Document {
@@ -88,94 +341,728 @@ impl Default for Document {
             filename: PathBuf::from("output.docx".to_string()),
             template: Some(PathBuf::from("reference.docx".to_string())),
             include: Some(vec![PathBuf::from("*".to_string())]),
+            exclude: vec![],
+            sections: vec![],
+            parts: vec![],
+            order: ChapterOrder::default(),
+            drafts: DraftMode::default(),
+            draft_placeholder: default_draft_placeholder(),
+            heading_levels: HeadingLevels::default(),
+            chapter_titles: ChapterTitles::default(),
+            chapter_break: ChapterBreak::default(),
+            unresolved_links: links::UnresolvedLinks::default(),
+            site_url: None,
+            strict: false,
+            toc: false,
+            toc_depth: default_toc_depth(),
+            toc_title: default_toc_title(),
+            list_of_figures: false,
+            list_of_tables: false,
+            title: None,
+            subtitle: None,
+            author: None,
+            date: None,
+            keywords: vec![],
+            subject: None,
+            properties: BTreeMap::new(),
+            title_block: false,
+            resource_paths: vec![],
+            svg: SvgMode::default(),
+            svg_dpi: default_svg_dpi(),
+            diagrams: default_diagrams(),
             offset_headings_by: None,
             append: None,
             prepend: None,
+            insert: vec![],
         }
     }
 }
 
 impl Document {
-    fn get_chapters(&self, context: &RenderContext) -> Result<Vec<PathBuf>> {
-        // valid globs
-        let patterns = self.get_patterns()?;
+    // replace the `{placeholder}`s in the filename and metadata
+    fn expand(mut self, values: &template::Values) -> Result<Self, DocumentError> {
+        let expand = |s: &str| values.expand(s).map_err(DocumentError::Config);
+        let expand_all = |list: Vec<String>| list.iter().map(|s| expand(s)).collect();
+        self.filename = PathBuf::from(expand(&self.filename.to_string_lossy())?);
+        self.title = self.title.as_deref().map(expand).transpose()?;
+        self.subtitle = self.subtitle.as_deref().map(expand).transpose()?;
+        self.author = self.author.map(expand_all).transpose()?;
+        self.date = self.date.as_deref().map(expand).transpose()?;
+        self.keywords = expand_all(self.keywords)?;
+        self.subject = self.subject.as_deref().map(expand).transpose()?;
+        for value in self.properties.values_mut() {
+            *value = expand(value)?;
+        }
+        Ok(self)
+    }
 
-        let mut ch: Vec<PathBuf> = Vec::new();
-        for item in context.book.iter() {
-            if let BookItem::Chapter(ref c) = *item {
-                if c.path.is_some() {
-                    let mut pattern_match = false;
-                    for p in &patterns {
-                        if p.matches_path(c.path.clone().unwrap().as_path()) {
-                            pattern_match = true;
-                        }
+    fn get_chapters<'a>(
+        &self,
+        context: &'a RenderContext,
+    ) -> Result<Vec<&'a Chapter>, DocumentError> {
+        // valid selectors and globs
+        let include = self.get_selectors()?;
+        let exclude = self.get_exclude_patterns()?;
+
+        let book = self.book_chapters(context);
+
+        let mut ch: Vec<&Chapter> = Vec::new();
+        for s in &include {
+            let matched: Vec<&String> = book
+                .iter()
+                .filter(|(c, part)| s.matches(c, *part))
+                .map(|(c, _)| &c.name)
+                .collect();
+            debug!(
+                "{}: include {} matched {:?}",
+                self.filename.display(),
+                s,
+                matched
+            );
+        }
+        match self.order {
+            ChapterOrder::Summary => {
+                for (c, part) in &book {
+                    if include.iter().any(|s| s.matches(c, *part)) {
+                        ch.push(c)
                     }
-                    if pattern_match == true {
-                        ch.push(c.path.clone().unwrap())
+                }
+            }
+            ChapterOrder::Include => {
+                // a chapter is placed by the first selector that matches it
+                for s in &include {
+                    for (c, part) in &book {
+                        if s.matches(c, *part) && !ch.iter().any(|e| std::ptr::eq(*e, *c)) {
+                            ch.push(c)
+                        }
                     }
                 }
             }
         }
+
+        // excludes are applied after includes, dropping anything they match
+        for p in &exclude {
+            let (dropped, kept): (Vec<&Chapter>, Vec<&Chapter>) = ch
+                .into_iter()
+                .partition(|c| c.path.as_ref().is_some_and(|path| p.matches_path(path)));
+            let dropped: Vec<&PathBuf> = dropped.iter().filter_map(|c| c.path.as_ref()).collect();
+            debug!(
+                "{}: exclude \"{}\" dropped {:?}",
+                self.filename.display(),
+                p,
+                dropped
+            );
+            ch = kept;
+        }
+
         if ch.is_empty() {
-            bail!("No markdown files match the specified include and/or exclude filters. Verify your filenames and filters are correct.")
+            return Err(DocumentError::NoChapters);
         };
         Ok(ch)
     }
 
-    // establish the list of globs based on the list of includes
-    fn get_patterns(&self) -> Result<Vec<Pattern>> {
-        let mut patterns: Vec<Pattern> = Vec::new();
-        for buf in self.include.clone().unwrap_or_default() {
-            patterns.push(
-                Pattern::new(buf.to_str().unwrap())
-                    .expect("Unable to create Pattern from provided include."),
-            );
+    // every chapter with a file on disk, plus the drafts if placeholders
+    // are wanted, in SUMMARY.md order along with the title of the part it sits under
+    fn book_chapters<'a>(&self, context: &'a RenderContext) -> Vec<(&'a Chapter, Option<&'a str>)> {
+        let with_drafts = self.drafts == DraftMode::Placeholder;
+        let mut book: Vec<(&Chapter, Option<&str>)> = Vec::new();
+        let mut part: Option<&str> = None;
+        for item in context.book.iter() {
+            match *item {
+                BookItem::PartTitle(ref title) => part = Some(title),
+                BookItem::Chapter(ref c) if c.path.is_some() || with_drafts => book.push((c, part)),
+                _ => {}
+            }
+        }
+        book
+    }
+
+    // establish the list of selectors based on the includes, sections and parts
+    fn get_selectors(&self) -> Result<Vec<Selector>, DocumentError> {
+        let mut selectors: Vec<Selector> =
+            compile_patterns(&self.include.clone().unwrap_or_default())?
+                .into_iter()
+                .map(Selector::Glob)
+                .collect();
+        for s in &self.sections {
+            selectors.push(Selector::parse_sections(s)?);
         }
-        // if patterns remains empty, use wildcard catch-all glob
-        if patterns.len() == 0 {
-            println!("No include value provided. Using wildcard glob.");
-            patterns.push(Pattern::new("*").expect("Error using wildcard glob."));
+        for p in &self.parts {
+            selectors.push(Selector::Part(p.clone()));
         }
-        if patterns.is_empty() {
-            bail!("No files matched the provided include / exclude filters.");
-        };
-        Ok(patterns)
+        // if selectors remains empty, use wildcard catch-all glob
+        if selectors.is_empty() {
+            debug!(
+                "{}: no include value provided. Using wildcard glob.",
+                self.filename.display()
+            );
+            selectors.push(Selector::Glob(
+                Pattern::new("*").expect("Error using wildcard glob."),
+            ));
+        }
+        Ok(selectors)
+    }
+
+    // establish the list of globs based on the list of excludes
+    fn get_exclude_patterns(&self) -> Result<Vec<Pattern>, DocumentError> {
+        compile_patterns(&self.exclude)
     }
 
     // filter the book content based on include/exclude values
-    fn get_filtered_content(&self, context: &RenderContext) -> Result<String> {
+    fn get_filtered_content(&self, context: &RenderContext) -> Result<String, DocumentError> {
         let mut content = String::new();
         let chapters = self.get_chapters(context)?;
+        let markdown_inserts = self.read_markdown_inserts(context)?;
+        let bookmarks = links::Bookmarks::new(
+            &src_dir(context),
+            chapters
+                .iter()
+                .filter(|ch| ch.path.is_some())
+                .map(|ch| (*ch, self.inserts_title(ch))),
+            markdown_inserts
+                .iter()
+                .flatten()
+                .map(|(path, markdown)| (path.as_path(), markdown.as_str())),
+            context.book.iter().filter_map(|item| match item {
+                BookItem::Chapter(ch) => Some(ch),
+                _ => None,
+            }),
+        );
+        let mut link_errors = Vec::new();
 
-        for item in context.book.iter() {
-            if let BookItem::Chapter(ref ch) = *item {
-                if let true = &ch.path.is_some() {
-                    if let true = chapters.contains(&ch.path.clone().unwrap()) {
-                        content.push_str(&ch.content);
-                        // chapter content in mdBook strips out newlines at the end of a file.
-                        // because we want to play it safe and add the MarkdownExtension
-                        // BlankBeforeHeader by default, this prevents all the level-1 headers
-                        // - h1's - from being accepted as headers, so they come out styled incorrectly.
-                        // To resolve this we simply append two newlines to the end of every chapter.
-                        content.push_str("\n\n");
-                    }
-                }
+        let inserts = self.insert_content(context, &markdown_inserts, &bookmarks, &mut link_errors);
+        let inserts_at = |position: &Position| -> String {
+            inserts
+                .iter()
+                .filter(|(p, _)| p == position)
+                .map(|(_, content)| content.as_str())
+                .collect()
+        };
+
+        content.push_str(&inserts_at(&Position::Before));
+        let lists = [
+            (self.toc, docx::TOC_MARKER),
+            (self.list_of_figures, docx::LIST_OF_FIGURES_MARKER),
+            (self.list_of_tables, docx::LIST_OF_TABLES_MARKER),
+        ];
+        for (_, marker) in lists.iter().filter(|(enabled, _)| *enabled) {
+            content.push_str(marker);
+            content.push_str("\n\n");
+        }
+        content.push_str(&inserts_at(&Position::AfterToc));
+        for ch in chapters {
+            // the first chapter also starts after the break when the table
+            // of contents or inserts come before it
+            if let Some(b) = self
+                .chapter_break
+                .as_break()
+                .filter(|_| !content.is_empty())
+            {
+                content.push_str(b.marker());
+                content.push_str("\n\n");
+            }
+            if let Some(path) = &ch.path {
+                let position = Position::BeforeChapter(path.clone());
+                content.push_str(&inserts_at(&position));
             }
+            if ch.path.is_none() {
+                content.push_str(&self.draft_content(ch));
+                continue;
+            }
+            content.push_str(&self.chapter_content(context, ch, &bookmarks, &mut link_errors));
+            // chapter content in mdBook strips out newlines at the end of a file.
+            // because we want to play it safe and add the MarkdownExtension
+            // BlankBeforeHeader by default, this prevents all the level-1 headers
+            // - h1's - from being accepted as headers, so they come out styled incorrectly.
+            // To resolve this we simply append two newlines to the end of every chapter.
+            content.push_str("\n\n");
+        }
+        content.push_str(&inserts_at(&Position::After));
+        if !link_errors.is_empty() {
+            return Err(DocumentError::UnresolvedLinks(link_errors));
         }
         if content.is_empty() {
-            bail!("The provided include/exclude filters do not match any content.");
+            return Err(DocumentError::NoChapters);
         };
         Ok(content)
     }
 
-    fn process(self, context: RenderContext) -> Result<()> {
+    // every file placed into the document, with prepend and append
+    // standing for files inserted before and after
+    fn inserts(&self) -> Vec<Insert> {
+        let prepend = self
+            .prepend
+            .iter()
+            .flatten()
+            .map(|p| Insert::new(p.clone(), insert::Kind::of(p), Position::Before));
+        let append = self
+            .append
+            .iter()
+            .flatten()
+            .map(|p| Insert::new(p.clone(), insert::Kind::of(p), Position::After));
+        prepend
+            .chain(self.insert.iter().cloned())
+            .chain(append)
+            .collect()
+    }
+
+    // the document's metadata as pandoc metadata fields, each falling back to
+    // the [book] table of book.toml. Fields given more than once become lists.
+    fn metadata(&self, context: &RenderContext) -> Vec<(String, String)> {
+        let book = &context.config.book;
+        let title = self.title.as_ref().or(book.title.as_ref());
+        let author = self.author.as_ref().unwrap_or(&book.authors);
+        let standard = [
+            ("title", title),
+            ("subtitle", self.subtitle.as_ref()),
+            ("date", self.date.as_ref()),
+            ("subject", self.subject.as_ref()),
+            ("description", book.description.as_ref()),
+            ("lang", book.language.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.to_string(), value?.clone())));
+        let lists = author
+            .iter()
+            .map(|a| ("author".to_string(), a.clone()))
+            .chain(
+                self.keywords
+                    .iter()
+                    .map(|k| ("keywords".to_string(), k.clone())),
+            );
+        // anything else pandoc writes to docProps/custom.xml
+        let custom = self.properties.iter().map(|(k, v)| (k.clone(), v.clone()));
+        standard.chain(lists).chain(custom).collect()
+    }
+
+    // the paragraph styles of the title block pandoc renders from the
+    // metadata, in the order it renders them
+    fn title_block_styles(&self, context: &RenderContext) -> Vec<&'static str> {
+        let metadata = self.metadata(context);
+        let fields: [(&str, &[&'static str]); 5] = [
+            ("title", &["Title"]),
+            ("subtitle", &["Subtitle"]),
+            ("author", &["Author"]),
+            ("date", &["Date"]),
+            ("abstract", &["AbstractTitle", "Abstract"]),
+        ];
+        let mut styles = Vec::new();
+        for (field, field_styles) in fields {
+            for _ in metadata.iter().filter(|(key, _)| key == field) {
+                styles.extend_from_slice(field_styles);
+            }
+        }
+        styles
+    }
+
+    // the path relative to the src dir and the content of each markdown
+    // insert, in the order of `inserts`, read ahead of the chapters so
+    // their headings have bookmarks
+    fn read_markdown_inserts(
+        &self,
+        context: &RenderContext,
+    ) -> Result<Vec<Option<(PathBuf, String)>>, DocumentError> {
+        self.inserts()
+            .into_iter()
+            .map(|ins| {
+                if ins.kind != insert::Kind::Markdown {
+                    return Ok(None);
+                }
+                let markdown =
+                    fs::read_to_string(context.root.join(&ins.path)).map_err(|source| {
+                        DocumentError::Read {
+                            path: ins.path.clone(),
+                            source,
+                        }
+                    })?;
+                Ok(Some((insert_path(context, &ins.path), markdown)))
+            })
+            .collect()
+    }
+
+    // the markdown standing for each insert, with its position. Markdown
+    // files go through the same steps as chapters, anything else leaves a
+    // marker for post-processing to replace
+    fn insert_content(
+        &self,
+        context: &RenderContext,
+        markdown_inserts: &[Option<(PathBuf, String)>],
+        bookmarks: &links::Bookmarks,
+        link_errors: &mut Vec<String>,
+    ) -> Vec<(Position, String)> {
+        let mut content = Vec::new();
+        for (i, (ins, markdown)) in self.inserts().into_iter().zip(markdown_inserts).enumerate() {
+            let markdown = match markdown {
+                Some((path, markdown)) => {
+                    self.prepare_markdown(context, markdown, path, 0, bookmarks, link_errors)
+                }
+                None => Insert::marker(i),
+            };
+            content.push((ins.position, format!("{}\n\n", markdown)));
+        }
+        content
+    }
+
+    // whether the chapter's SUMMARY.md title is inserted above its content
+    fn inserts_title(&self, ch: &Chapter) -> bool {
+        match self.chapter_titles {
+            ChapterTitles::Never => false,
+            ChapterTitles::Always => true,
+            ChapterTitles::Auto => !markdown::starts_with_heading(&ch.content),
+        }
+    }
+
+    // the markdown for a single chapter, with its title, bookmarks,
+    // links and heading levels applied
+    fn chapter_content(
+        &self,
+        context: &RenderContext,
+        ch: &Chapter,
+        bookmarks: &links::Bookmarks,
+        link_errors: &mut Vec<String>,
+    ) -> String {
+        let content = if self.inserts_title(ch) {
+            format!("# {}\n\n{}", ch.name, ch.content)
+        } else {
+            ch.content.clone()
+        };
+
+        let path = ch.path.clone().unwrap_or_default();
+        let depth = ch.parent_names.len();
+        self.prepare_markdown(context, &content, &path, depth, bookmarks, link_errors)
+    }
+
+    // the steps every piece of markdown in the document goes through. `path`
+    // is where the markdown sits relative to the src dir and `depth` is how
+    // deeply it is nested in SUMMARY.md
+    fn prepare_markdown(
+        &self,
+        context: &RenderContext,
+        content: &str,
+        path: &Path,
+        depth: usize,
+        bookmarks: &links::Bookmarks,
+        link_errors: &mut Vec<String>,
+    ) -> String {
+        // give every heading a fixed identifier and point links between
+        // chapters at them, so they become bookmarks in the document
+        let content = markdown::set_heading_ids(content, &bookmarks.heading_ids(path));
+        let (content, errors) = bookmarks.rewrite_links(
+            &content,
+            path,
+            self.unresolved_links,
+            self.site_url.as_deref(),
+        );
+        link_errors.extend(errors);
+
+        // images are written relative to their chapter, but pandoc sees every
+        // chapter at once and looks them up from the src dir
+        let src_dir = src_dir(context);
+        let resource_path = self.resource_path(context);
+        let content = markdown::rewrite_images(&content, |dest| {
+            image_source(&src_dir, &resource_path, path, dest)
+        });
+
+        let content = markdown::replace_page_break_comments(&content, docx::Break::Page.marker());
+
+        match self.heading_levels {
+            HeadingLevels::Flat => content,
+            HeadingLevels::Nesting => markdown::shift_headings(&content, depth),
+        }
+    }
+
+    // placeholder content for a draft chapter. The heading sits at the level
+    // the chapter's SUMMARY.md section number implies so numbering is kept.
+    fn draft_content(&self, ch: &Chapter) -> String {
+        let level = ch.number.as_ref().map_or(1, |n| n.0.len().max(1));
+        format!(
+            "{} {}\n\n{}\n\n",
+            "#".repeat(level),
+            ch.name,
+            self.draft_placeholder
+        )
+    }
+
+    // check the selectors, the link and image targets of the selected chapters
+    // and the configured files before anything is built. In strict mode any
+    // problem fails the document, otherwise each is logged as a warning.
+    fn validate(&self, context: &RenderContext) -> Result<(), DocumentError> {
+        let mut problems = Vec::new();
+
+        if self.svg_dpi == 0 {
+            return Err(DocumentError::Config(
+                "svg_dpi must be greater than 0.".to_string(),
+            ));
+        }
+
+        if !(1..=9).contains(&self.toc_depth) {
+            return Err(DocumentError::Config(format!(
+                "toc_depth must be between 1 and 9, not {}.",
+                self.toc_depth
+            )));
+        }
+
+        let book = self.book_chapters(context);
+        for s in self.get_selectors()? {
+            if !book.iter().any(|(c, part)| s.matches(c, *part)) {
+                problems.push(validate::Problem::UnmatchedSelector(s.to_string()));
+            }
+        }
+
+        let src_dir = src_dir(context);
+        let resource_paths: Vec<PathBuf> = self
+            .resource_paths
+            .iter()
+            .map(|p| context.root.join(p))
+            .collect();
+        let check_targets = |path: &Path, content: &str| {
+            validate::check_targets(&src_dir, &resource_paths, path, content)
+        };
+        let chapters = self.get_chapters(context).unwrap_or_default();
+        for ch in &chapters {
+            if let Some(path) = &ch.path {
+                problems.extend(check_targets(path, &ch.content));
+            }
+        }
+        for ins in self.inserts() {
+            let file = context.root.join(&ins.path);
+            if ins.kind == insert::Kind::Markdown {
+                if let Ok(content) = fs::read_to_string(&file) {
+                    let path = insert_path(context, &ins.path);
+                    problems.extend(check_targets(&path, &content));
+                }
+            }
+        }
+        for ins in &self.insert {
+            if let Position::BeforeChapter(chapter) = &ins.position {
+                // its marker would never be emitted, leaving the insert out
+                // of the document, so this fails even when not strict
+                if !chapters.iter().any(|ch| ch.path.as_ref() == Some(chapter)) {
+                    return Err(DocumentError::Config(format!(
+                        "insert \"{}\" is placed before \"{}\", which is not in the document.",
+                        ins.path.display(),
+                        chapter.display()
+                    )));
+                }
+            }
+        }
+
+        let files = [("template", &self.template)]
+            .into_iter()
+            .filter_map(|(option, path)| path.as_ref().map(|p| (option, p)))
+            .chain(self.prepend.iter().flatten().map(|p| ("prepend", p)))
+            .chain(self.append.iter().flatten().map(|p| ("append", p)))
+            .chain(self.insert.iter().map(|i| ("insert", &i.path)))
+            .chain(self.resource_paths.iter().map(|p| ("resource_paths", p)));
+        for (option, path) in files {
+            if !context.root.join(path).exists() {
+                problems.push(validate::Problem::MissingFile {
+                    option,
+                    path: path.clone(),
+                });
+            }
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+        if self.strict {
+            return Err(validate::ValidationError { problems }.into());
+        }
+        for problem in problems {
+            warn!("{}: {}", self.filename.display(), problem);
+        }
+        Ok(())
+    }
+
+    fn process(self, context: RenderContext, cache: &cache::Cache) -> Result<(), DocumentError> {
+        let output = self.output_path(&context)?;
+
+        // catch missing files and broken references before running pandoc
+        self.validate(&context)?;
+
         // get the static, non-configurable pandoc configuration
         let mut pandoc_config = PandocConfig::default();
         pandoc_config.assign_options(&context, &self);
 
         // set the content
         let content = self.get_filtered_content(&context)?;
+        let content = self.render_diagrams(&context, &content);
+        let (content, svgs) = self.convert_svgs(&context, &content);
+
+        // skip the document entirely if nothing it's built from has changed
+        let hash = self.fingerprint(&context, &pandoc_config, &content);
+        if cache.is_fresh(&self.filename, &hash, &output) {
+            info!(
+                "{}: unchanged since the last build, skipping.",
+                self.filename.display()
+            );
+            return Ok(());
+        }
+        if let Some(dir) = output.parent() {
+            fs::create_dir_all(dir).map_err(|source| DocumentError::Write {
+                path: dir.to_path_buf(),
+                source,
+            })?;
+        }
+        let filename = self.filename.clone();
+        let result = self.build(&context, pandoc_config, content, &svgs, &output);
+        cache.update(&filename, result.is_ok().then_some(hash));
+        result
+    }
+
+    // replace the diagram code blocks in the content with images rendered
+    // from them. A block whose diagram cannot be rendered is left as it is.
+    fn render_diagrams(&self, context: &RenderContext, content: &str) -> String {
+        let dir = context.destination.join(cache::IMAGE_DIR);
+        let mut renderer = diagram::Renderer::new(&self.diagrams, dir);
+        markdown::replace_code_blocks(content, |info, code| {
+            let (language, caption) = diagram::parse_info(info);
+            let image = renderer.render(language, code).unwrap_or_else(|e| {
+                warn!(
+                    "{}: {:#}, leaving the diagram as a code block.",
+                    self.filename.display(),
+                    e
+                );
+                None
+            })?;
+            let markdown = markdown::image(caption.unwrap_or_default(), &image.to_string_lossy());
+            // with implicit figures the alt text already becomes the caption
+            Some(match caption {
+                Some(caption) if !self.list_of_figures => format!("{}\n\n*{}*", markdown, caption),
+                _ => markdown,
+            })
+        })
+    }
+
+    // swap the SVG images in the content for PNGs that every version of Word
+    // can show, returning the images converted
+    fn convert_svgs(
+        &self,
+        context: &RenderContext,
+        content: &str,
+    ) -> (String, Vec<svg::Converted>) {
+        if self.svg == SvgMode::Keep {
+            return (content.to_string(), Vec::new());
+        }
+        let resource_path = self.resource_path(context);
+        let dir = context.destination.join(cache::IMAGE_DIR);
+        let mut converter = svg::Converter::new(dir, &resource_path, self.svg_dpi);
+        let content = markdown::rewrite_images(content, |dest| {
+            converter.convert(dest).unwrap_or_else(|e| {
+                warn!(
+                    "{}: {:#}, using the SVG as it is.",
+                    self.filename.display(),
+                    e
+                );
+                None
+            })
+        });
+        (content, converter.into_converted())
+    }
+
+    // where the document is written: `filename` within the renderer's
+    // destination, which follows `build.build-dir` and `--dest-dir`
+    fn output_path(&self, context: &RenderContext) -> Result<PathBuf, DocumentError> {
+        let escapes = || {
+            DocumentError::Config(format!(
+                "filename \"{}\" is outside the output directory {}",
+                self.filename.display(),
+                context.destination.display()
+            ))
+        };
+        // an absolute filename is accepted when it points inside the destination
+        let filename = match self.filename.is_absolute() {
+            true => self
+                .filename
+                .strip_prefix(&context.destination)
+                .map_err(|_| escapes())?,
+            false => &self.filename,
+        };
+        let mut relative = PathBuf::new();
+        for component in filename.components() {
+            match component {
+                Component::Normal(name) => relative.push(name),
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if !relative.pop() {
+                        return Err(escapes());
+                    }
+                }
+                Component::RootDir | Component::Prefix(_) => return Err(escapes()),
+            }
+        }
+        if relative.as_os_str().is_empty() {
+            return Err(DocumentError::Config("filename is empty".to_string()));
+        }
+        Ok(context.destination.join(relative))
+    }
+
+    // the directories pandoc looks for images in: the src dir, then any
+    // configured `resource_paths`
+    fn resource_path(&self, context: &RenderContext) -> Vec<PathBuf> {
+        let mut dirs = vec![src_dir(context)];
+        dirs.extend(self.resource_paths.iter().map(|p| context.root.join(p)));
+        dirs
+    }
 
+    // a hash over the content, the configuration and every file the document is built from
+    fn fingerprint(&self, context: &RenderContext, config: &PandocConfig, content: &str) -> String {
+        let mut fingerprint = cache::Fingerprint::new();
+        fingerprint
+            .add("document", format!("{:?}", self).as_bytes())
+            .add("options", format!("{:?}", config.options).as_bytes())
+            .add(
+                "extensions",
+                format!("{:?}", config.input_extensions).as_bytes(),
+            )
+            .add("content", content.as_bytes());
+        let files = self
+            .template
+            .iter()
+            .chain(self.prepend.iter().flatten())
+            .chain(self.append.iter().flatten())
+            .chain(self.insert.iter().map(|i| &i.path));
+        for path in files {
+            fingerprint.add_file(&path.to_string_lossy(), &context.root.join(path));
+        }
+        // images are found on the resource path the way pandoc finds them,
+        // so an edited image rebuilds the document
+        let resource_path = self.resource_path(context);
+        for dest in markdown::images(content) {
+            let path = match Path::new(&dest).is_absolute() {
+                true => Some(PathBuf::from(&dest)),
+                false => validate::local_path(&dest).and_then(|target| {
+                    resource_path
+                        .iter()
+                        .map(|dir| dir.join(&target))
+                        .find(|path| path.is_file())
+                }),
+            };
+            match path {
+                Some(path) => fingerprint.add_file(&dest, &path),
+                None => fingerprint.add(&dest, b"<missing>"),
+            };
+        }
+        fingerprint.finish()
+    }
+
+    fn build(
+        self,
+        context: &RenderContext,
+        pandoc_config: PandocConfig,
+        content: String,
+        svgs: &[svg::Converted],
+        output: &Path,
+    ) -> Result<(), DocumentError> {
+        // logged rather than printed by pandoc so it stays with the rest
+        // of the document's output when documents are built concurrently
+        debug!(
+            "{}: running pandoc with {:?}, input extensions {:?}",
+            self.filename.display(),
+            pandoc_config.options,
+            pandoc_config.input_extensions
+        );
         let mut pandoc = Pandoc::new();
         pandoc.add_options(pandoc_config.options.as_slice());
 
@@ -185,55 +1072,261 @@ impl Document {
         );
         pandoc.set_input(pandoc::InputKind::Pipe(content.to_string()));
         pandoc.set_output_format(pandoc::OutputFormat::Docx, pandoc_config.output_extensions);
-        pandoc.set_output(OutputKind::File(self.filename.clone()));
-
-        pandoc.set_show_cmdline(true);
+        pandoc.set_output(OutputKind::File(output.to_path_buf()));
 
         // execute the pandoc cli and bail on an error
         // If pandoc errored, present the error in our DocumentError
-        if let Err(e) = pandoc.execute() {
-            bail!(e);
+        pandoc.execute()?;
+
+        self.post_process(context, svgs, output)
+            .map_err(DocumentError::PostProcess)
+    }
+
+    // work done directly on the .docx package once pandoc has finished
+    fn post_process(
+        &self,
+        context: &RenderContext,
+        svgs: &[svg::Converted],
+        output: &Path,
+    ) -> anyhow::Result<()> {
+        let mut package = docx::Package::open(output)?;
+        // swap the break markers for real page and section breaks
+        docx::apply_breaks(&mut package)?;
+        // pandoc shows the title, subtitle, author and date above the content.
+        // Shifting headings up turns a leading level 1 heading into the title,
+        // which is content rather than metadata, so it is kept.
+        if !self.title_block {
+            docx::remove_title_block(
+                &mut package,
+                &self.title_block_styles(context),
+                self.offset_headings_by.is_some_and(|n| n < 0),
+            )?;
+        }
+        if self.toc {
+            let instruction = format!(r#"TOC \o "1-{}" \h \z \u"#, self.toc_depth);
+            docx::insert_toc(
+                &mut package,
+                docx::TOC_MARKER,
+                &self.toc_title,
+                &instruction,
+            )?;
+        }
+        // caption every figure and table so the lists have entries to show
+        if self.list_of_figures {
+            docx::number_captions(&mut package, "ImageCaption", "Figure")?;
+            let instruction = r#"TOC \h \z \c "Figure""#;
+            docx::insert_list(
+                &mut package,
+                docx::LIST_OF_FIGURES_MARKER,
+                "List of Figures",
+                instruction,
+            )?;
+        }
+        if self.list_of_tables {
+            docx::number_captions(&mut package, "TableCaption", "Table")?;
+            let instruction = r#"TOC \h \z \c "Table""#;
+            docx::insert_list(
+                &mut package,
+                docx::LIST_OF_TABLES_MARKER,
+                "List of Tables",
+                instruction,
+            )?;
         }
 
-        // now the markdown > docx has completed, combine it
-        // with any append/prepend files if specified
-        self.combine_sections(&context)?;
+        if self.svg == SvgMode::PngAndSvg {
+            svg::embed(&mut package, svgs)?;
+        }
 
-        Ok(())
+        // put the inserted files in place of their markers
+        for (i, ins) in self.inserts().iter().enumerate() {
+            let path = context.root.join(&ins.path);
+            let marker = Insert::marker(i);
+            match ins.kind {
+                insert::Kind::Docx => {
+                    let source = docx::Package::open(&path)?;
+                    let warnings = merge::insert(&mut package, &source, &marker)?;
+                    // content that was left out fails the document in strict mode
+                    if self.strict && !warnings.is_empty() {
+                        anyhow::bail!("{}: {}", ins.path.display(), warnings.join("; "));
+                    }
+                    for warning in warnings {
+                        warn!(
+                            "{}: {}: {}",
+                            self.filename.display(),
+                            ins.path.display(),
+                            warning
+                        );
+                    }
+                }
+                insert::Kind::RawOpenxml => {
+                    let content = fs::read_to_string(&path)
+                        .with_context(|| format!("Unable to read {}", path.display()))?;
+                    merge::insert_raw(&mut package, &content, &marker)?
+                }
+                insert::Kind::Markdown => {}
+            }
+        }
+        package.save(output)
     }
+}
 
-    fn combine_sections(self, context: &RenderContext) -> Result<()> {
-        let mut parts: Vec<PathBuf> = vec![];
+// the directory chapter paths are relative to, `src` in the [book] table
+fn src_dir(context: &RenderContext) -> PathBuf {
+    context.root.join(&context.config.book.src)
+}
 
-        // init our parts list with the prepends
-        if let Some(paths) = &self.prepend {
-            for p in paths {
-                parts.push(context.root.clone().join(p))
+// the source of an image written in the chapter at `chapter`, made relative
+// to the src dir. An image that is not beside the chapter but is found on the
+// resource path is left alone, as is one in a chapter at the src root.
+fn image_source(
+    src_dir: &Path,
+    resource_path: &[PathBuf],
+    chapter: &Path,
+    dest: &str,
+) -> Option<String> {
+    let target = validate::local_path(dest)?;
+    let dir = chapter.parent().filter(|dir| !dir.as_os_str().is_empty())?;
+    let beside = src_dir.join(dir).join(&target).exists();
+    if !beside && resource_path.iter().any(|p| p.join(&target).exists()) {
+        return None;
+    }
+    let mut parts: Vec<String> = Vec::new();
+    for component in dir.join(&target).components() {
+        match component {
+            Component::ParentDir if parts.last().is_some_and(|p| p != "..") => {
+                parts.pop();
             }
-        };
-        // add our main file to the vec
-        parts.push(context.root.clone().join("book/docx").join(self.filename.clone()));
-        // complete our parts list with any appends
-        if let Some(paths) = &self.append {
-            for p in paths {
-                parts.push(context.root.clone().join(p))
+            Component::ParentDir => parts.push("..".to_string()),
+            Component::Normal(name) => parts.push(name.to_string_lossy().into_owned()),
+            _ => {}
+        }
+    }
+    // keep any fragment or query on the original source
+    let suffix = &dest[dest.find(['#', '?']).unwrap_or(dest.len())..];
+    Some(format!("{}{}", parts.join("/"), suffix))
+}
+
+// where a markdown insert sits relative to the src dir. A file outside it
+// is reached through `..`, so its images and links are found beside it.
+fn insert_path(context: &RenderContext, path: &Path) -> PathBuf {
+    links::relative_to(&src_dir(context), &context.root.join(path))
+        .unwrap_or_else(|| PathBuf::from(path.file_name().unwrap_or_default()))
+}
+
+fn default_draft_placeholder() -> String {
+    "This section is not yet written.".to_string()
+}
+
+fn default_toc_depth() -> u32 {
+    3
+}
+
+fn default_toc_title() -> String {
+    "Contents".to_string()
+}
+
+fn default_svg_dpi() -> u32 {
+    192
+}
+
+fn default_diagrams() -> BTreeMap<String, String> {
+    BTreeMap::from([
+        ("dot".to_string(), "dot -Tpng".to_string()),
+        (
+            "mermaid".to_string(),
+            "mmdc -i {input} -o {output}".to_string(),
+        ),
+    ])
+}
+
+/// A rule used to select chapters from the book.
+#[derive(Debug)]
+enum Selector {
+    /// a glob over `Chapter::path`. Draft chapters have an empty path,
+    /// so only catch-all globs such as `*` select them.
+    Glob(Pattern),
+    /// an inclusive range of SUMMARY.md section numbers. A chapter matches
+    /// when its number, truncated to the length of each bound, sits within
+    /// the range, so subchapters follow their parent.
+    Sections(Vec<u32>, Vec<u32>),
+    /// the title of a SUMMARY.md part
+    Part(String),
+}
+
+impl Selector {
+    // parse a section selector such as "3", "3.1." or "3.1-3.4"
+    fn parse_sections(value: &str) -> Result<Self, DocumentError> {
+        let (start, end) = value.split_once('-').unwrap_or((value, value));
+        let (start, end) = (parse_section_number(start)?, parse_section_number(end)?);
+        if start.is_empty() || end.is_empty() {
+            return Err(DocumentError::Config(format!(
+                "Invalid section selector \"{}\".",
+                value
+            )));
+        }
+        Ok(Selector::Sections(start, end))
+    }
+
+    fn matches(&self, chapter: &Chapter, part: Option<&str>) -> bool {
+        match self {
+            Selector::Glob(p) => {
+                p.matches_path(chapter.path.as_deref().unwrap_or_else(|| Path::new("")))
             }
-        };
+            Selector::Sections(start, end) => chapter.number.as_ref().is_some_and(|n| {
+                let truncated = |len: usize| &n.0[..n.0.len().min(len)];
+                truncated(start.len()) >= start.as_slice() && truncated(end.len()) <= end.as_slice()
+            }),
+            Selector::Part(title) => part == Some(title.as_str()),
+        }
+    }
+}
 
-        // have pandoc merge them
-        let mut pandoc = Pandoc::new();
-        pandoc.set_output(OutputKind::File(self.filename.clone()));
-        for part in parts {
-            pandoc.add_input(&part);
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |n: &[u32]| {
+            n.iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+                .join(".")
         };
+        match self {
+            Selector::Glob(p) => write!(f, "\"{}\"", p),
+            Selector::Sections(start, end) if start == end => write!(f, "section {}", join(start)),
+            Selector::Sections(start, end) => {
+                write!(f, "sections {}-{}", join(start), join(end))
+            }
+            Selector::Part(title) => write!(f, "part \"{}\"", title),
+        }
+    }
+}
 
-        let mut config = PandocConfig::default();
-        config.assign_options(context, &self);
-        pandoc.add_options(config.options.as_slice());
-        pandoc.execute()?;
+// parse a section number such as "3.1." into its components
+fn parse_section_number(value: &str) -> Result<Vec<u32>, DocumentError> {
+    value
+        .trim()
+        .trim_end_matches('.')
+        .split('.')
+        .map(|n| {
+            n.parse::<u32>().map_err(|_| {
+                DocumentError::Config(format!("Invalid section number \"{}\".", value))
+            })
+        })
+        .collect()
+}
 
-        Ok(())
+// compile a list of unix shell style globs into Patterns
+fn compile_patterns(globs: &[PathBuf]) -> Result<Vec<Pattern>, DocumentError> {
+    let mut patterns: Vec<Pattern> = Vec::new();
+    for buf in globs {
+        patterns.push(Pattern::new(&buf.to_string_lossy()).map_err(|e| {
+            DocumentError::Config(format!(
+                "Unable to create Pattern from \"{}\": {}",
+                buf.display(),
+                e
+            ))
+        })?);
     }
+    Ok(patterns)
 }
 
 pub struct PandocConfig {
@@ -251,6 +1344,7 @@ impl Default for PandocConfig {
                 MarkdownExtension::RawHtml,
                 MarkdownExtension::AutolinkBareUris,
                 MarkdownExtension::AutoIdentifiers,
+                MarkdownExtension::HeaderAttributes,
                 MarkdownExtension::HardLineBreaks,
                 MarkdownExtension::BlankBeforeHeader,
                 MarkdownExtension::TableCaptions,
@@ -271,49 +1365,46 @@ impl PandocConfig {
     fn assign_options(&mut self, context: &RenderContext, doc: &Document) -> &Self {
         // directory of book.toml and root of where we'll look for content
         let data_dir = context.root.clone();
-        // path of the src directory
-        let src_path = PathBuf::from(&data_dir).join("src");
         self.options = vec![
             PandocOption::DataDir(data_dir.clone()),
-            PandocOption::ResourcePath(vec![src_path]),
+            PandocOption::ResourcePath(doc.resource_path(context)),
             PandocOption::AtxHeaders,
             PandocOption::ReferenceLinks,
         ];
 
+        // an image alone in a paragraph becomes a figure with its alt text as
+        // the caption, which the list of figures is built from
+        if doc.list_of_figures {
+            self.input_extensions
+                .push(MarkdownExtension::ImplicitFigures);
+        }
+
+        // core and custom document properties
+        for (key, value) in doc.metadata(context) {
+            self.options.push(PandocOption::Meta(key, Some(value)));
+        }
+
         // set the shift-heading-level option if specified
         if let Some(i) = doc.offset_headings_by {
             self.options.push(PandocOption::ShiftHeadingLevelBy(i))
         };
         // set the reference template if specified
         if let Some(path) = &doc.template {
-            self.options.push(PandocOption::ReferenceDoc(data_dir.clone().join(path)))
+            self.options
+                .push(PandocOption::ReferenceDoc(data_dir.clone().join(path)))
         };
-        // set the file to prepend to the document if specified
-        if let Some(path) = &doc.prepend {
-            for p in path {
-                self.options.push(PandocOption::IncludeBeforeBody(data_dir.clone().join(p.to_owned())))
-            }
-        };
-        // set the file(s) to append to the document if specified
-        if let Some(path) = &doc.append {
-            for p in path {
-                self.options.push(PandocOption::IncludeAfterBody(data_dir.join(p.to_owned())))
-            }
-        };
-
         self
     }
 }
 
-fn run() -> Result<()> {
+fn run() -> Result<(), Error> {
     let mut stdin = io::stdin();
-    let ctx = RenderContext::from_json(&mut stdin)
-        .expect("Error with the input data. Is everything formatted correctly?");
+    let ctx = RenderContext::from_json(&mut stdin).map_err(|e| Error::Input(format!("{:#}", e)))?;
 
     let list: DocumentList = ctx
         .config
         .get_deserialized_opt("output.docx")
-        .expect("Error reading \"output.docx\" configuration in book.toml. Check that all values are of the correct data type.")
+        .map_err(|e| Error::Config(format!("{:#}", e)))?
         .unwrap_or_default();
 
     //println!("List of documents: {:#?}", list.documents);
@@ -324,10 +1415,229 @@ fn run() -> Result<()> {
 }
 
 fn main() {
-    init_logger();
+    logging::init_logger();
 
     let r = run();
     if let Err(e) = r {
         error!("An error has occurred while creating the document.\n{}", e);
+        // a non-zero exit lets mdbook, and any CI running it, see the failure
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mdbook::{book::Book, book::SectionNumber, Config};
+
+    fn chapter(number: &[u32]) -> Chapter {
+        let mut chapter = Chapter::new("Chapter", String::new(), "chapter.md", Vec::new());
+        chapter.number = Some(SectionNumber(number.to_vec()));
+        chapter
+    }
+
+    fn sections(value: &str) -> Selector {
+        Selector::parse_sections(value).unwrap()
+    }
+
+    #[test]
+    fn section_selectors() {
+        assert!(matches!(
+            sections("3.1."),
+            Selector::Sections(start, end) if start == [3, 1] && end == [3, 1]
+        ));
+        assert!(matches!(
+            sections(" 3.1 - 3.4 "),
+            Selector::Sections(start, end) if start == [3, 1] && end == [3, 4]
+        ));
+        for invalid in ["", "3.x", "3-", "-3", "3..1"] {
+            assert!(
+                Selector::parse_sections(invalid).is_err(),
+                "{:?} parsed",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn sections_include_subchapters() {
+        let selector = sections("3");
+        assert!(selector.matches(&chapter(&[3]), None));
+        assert!(selector.matches(&chapter(&[3, 2, 1]), None));
+        assert!(!selector.matches(&chapter(&[4]), None));
+        assert!(!selector.matches(&chapter(&[2, 9]), None));
+    }
+
+    #[test]
+    fn section_ranges_truncate_to_their_bounds() {
+        let selector = sections("3.1-3.4");
+        assert!(selector.matches(&chapter(&[3, 1]), None));
+        assert!(selector.matches(&chapter(&[3, 4, 2]), None));
+        assert!(!selector.matches(&chapter(&[3, 5]), None));
+        assert!(!selector.matches(&chapter(&[2, 1]), None));
+        // the parent chapter is shorter than the bounds and sits before them
+        assert!(!selector.matches(&chapter(&[3]), None));
+
+        let selector = sections("2-3.1");
+        assert!(selector.matches(&chapter(&[2, 7]), None));
+        assert!(selector.matches(&chapter(&[3]), None));
+        assert!(selector.matches(&chapter(&[3, 1, 5]), None));
+        assert!(!selector.matches(&chapter(&[3, 2]), None));
+    }
+
+    #[test]
+    fn unnumbered_chapters_match_no_sections() {
+        let mut draft = chapter(&[1]);
+        draft.number = None;
+        assert!(!sections("1").matches(&draft, None));
+        assert!(Selector::Part("Guide".to_string()).matches(&draft, Some("Guide")));
+        assert!(!Selector::Part("Guide".to_string()).matches(&draft, Some("Other")));
+    }
+
+    fn output_path(filename: &str) -> Result<PathBuf, DocumentError> {
+        let context = RenderContext::new("/book", Book::new(), Config::default(), "/book/out");
+        let document = Document {
+            filename: PathBuf::from(filename),
+            ..Default::default()
+        };
+        document.output_path(&context)
+    }
+
+    #[test]
+    fn output_paths_stay_in_the_destination() {
+        let inside = [
+            ("guide.docx", "/book/out/guide.docx"),
+            ("./v1/guide.docx", "/book/out/v1/guide.docx"),
+            ("v1/../v2/guide.docx", "/book/out/v2/guide.docx"),
+            ("/book/out/v1/guide.docx", "/book/out/v1/guide.docx"),
+        ];
+        for (filename, expected) in inside {
+            assert_eq!(output_path(filename).unwrap(), Path::new(expected));
+        }
+    }
+
+    #[test]
+    fn output_paths_outside_the_destination_are_rejected() {
+        for filename in [
+            "../guide.docx",
+            "v1/../../guide.docx",
+            "/book/guide.docx",
+            "/tmp/guide.docx",
+        ] {
+            let error = output_path(filename).unwrap_err().to_string();
+            assert!(
+                error.contains("is outside the output directory"),
+                "{}: {}",
+                filename,
+                error
+            );
+        }
+        for filename in ["", ".", "v1/.."] {
+            let error = output_path(filename).unwrap_err().to_string();
+            assert!(
+                error.contains("filename is empty"),
+                "{}: {}",
+                filename,
+                error
+            );
+        }
+    }
+
+    // an empty directory for a test to create files in, named after it
+    pub(crate) fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mdbook-docx-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // create each file, with its directories, under `root`
+    pub(crate) fn create_files(root: &Path, files: &[&str]) {
+        for file in files {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, file).unwrap();
+        }
+    }
+
+    #[test]
+    fn image_sources_are_made_relative_to_the_src_dir() {
+        let root = scratch_dir("image-sources");
+        create_files(
+            &root,
+            &["src/ch/img/a.png", "src/img/root.png", "res/logo.png"],
+        );
+        let src = root.join("src");
+        let resource_path = [src.clone(), root.join("res")];
+        let cases = [
+            // a chapter at the src root needs no rewriting
+            ("intro.md", "img/root.png", None),
+            ("intro.md", "../res/logo.png", None),
+            // beside the chapter
+            ("ch/one.md", "img/a.png", Some("ch/img/a.png")),
+            ("ch/one.md", "./img/a.png", Some("ch/img/a.png")),
+            ("ch/one.md", "../img/root.png", Some("img/root.png")),
+            ("ch/sub/two.md", "../img/a.png", Some("ch/img/a.png")),
+            // found only on the resource path, as before chapters had their own
+            ("ch/one.md", "img/root.png", None),
+            ("ch/one.md", "logo.png", None),
+            // missing everywhere, where pandoc reports it beside the chapter
+            ("ch/one.md", "img/missing.png", Some("ch/img/missing.png")),
+            // suffixes are kept
+            ("ch/one.md", "img/a.png#layer", Some("ch/img/a.png#layer")),
+            ("ch/one.md", "img/a.png?v=2", Some("ch/img/a.png?v=2")),
+            // not local files
+            ("ch/one.md", "https://example.com/a.png", None),
+            ("ch/one.md", "data:image/png;base64,AAAA", None),
+            ("ch/one.md", "/img/a.png", None),
+        ];
+        for (chapter, dest, expected) in cases {
+            assert_eq!(
+                image_source(&src, &resource_path, Path::new(chapter), dest).as_deref(),
+                expected,
+                "{} in {}",
+                dest,
+                chapter
+            );
+        }
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn fingerprints_change_with_images() {
+        let root = scratch_dir("document-fingerprint");
+        create_files(&root, &["src/img/a.png", "res/logo.png"]);
+        let context = RenderContext::new(&root, Book::new(), Config::default(), root.join("book"));
+        let document = Document {
+            resource_paths: vec![PathBuf::from("res")],
+            ..Document::default()
+        };
+        let content = "![](img/a.png) ![](logo.png)";
+        let fingerprint = || document.fingerprint(&context, &PandocConfig::default(), content);
+
+        let first = fingerprint();
+        assert_eq!(fingerprint(), first);
+        fs::write(root.join("src/img/a.png"), b"edited").unwrap();
+        let edited = fingerprint();
+        assert_ne!(edited, first);
+        fs::write(root.join("res/logo.png"), b"edited").unwrap();
+        assert_ne!(fingerprint(), edited);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn diagrams_with_a_missing_program_stay_code_blocks() {
+        let root = scratch_dir("render-diagrams");
+        let context = RenderContext::new(&root, Book::new(), Config::default(), root.join("book"));
+        let document = Document {
+            diagrams: BTreeMap::from([(
+                "dot".to_string(),
+                "mdbook-docx-no-such-program".to_string(),
+            )]),
+            ..Document::default()
+        };
+        let content = "Before.\n\n```dot caption=\"Flow\"\ndigraph { a -> b }\n```\n\nAfter.\n";
+        assert_eq!(document.render_diagrams(&context, content), content);
+        fs::remove_dir_all(root).unwrap();
     }
 }
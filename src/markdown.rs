@@ -0,0 +1,492 @@
+//! Markdown rewriting applied to chapter content before it is handed to pandoc.
+
+use mdbook::utils::unique_id_from_content;
+use pulldown_cmark::{CodeBlockKind, Event, LinkType, Options, Parser, Tag, TagEnd};
+use regex::Regex;
+use std::{collections::HashMap, ops::Range, sync::LazyLock};
+
+// the src attribute of a raw html <img> tag
+static IMG_SRC: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?i)<img\b[^>]*?\bsrc\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap());
+
+// the extensions mdBook enables when it renders chapters
+fn options() -> Options {
+    Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_HEADING_ATTRIBUTES
+}
+
+// apply a list of replacements to the content. Ranges must not overlap.
+fn apply_edits(content: &str, mut edits: Vec<(Range<usize>, String)>) -> String {
+    edits.sort_by_key(|(range, _)| range.start);
+    let mut out = String::with_capacity(content.len());
+    let mut last = 0;
+    for (range, text) in edits {
+        out.push_str(&content[last..range.start]);
+        out.push_str(&text);
+        last = range.end;
+    }
+    out.push_str(&content[last..]);
+    out
+}
+
+/// Shift every heading in the content down by `by` levels, capped at level 6.
+/// Setext headings are rewritten as ATX headings so they can be shifted.
+pub fn shift_headings(content: &str, by: usize) -> String {
+    if by == 0 {
+        return content.to_string();
+    }
+    let mut edits = Vec::new();
+    for (event, range) in Parser::new_ext(content, options()).into_offset_iter() {
+        if let Event::Start(Tag::Heading { level, .. }) = event {
+            let level = (level as usize + by).min(6);
+            let source = &content[range.clone()];
+            let indent = source.len() - source.trim_start().len();
+            let source = source.trim_start();
+            if source.starts_with('#') {
+                // ATX heading, replace the run of hashes
+                let hashes = source.len() - source.trim_start_matches('#').len();
+                let start = range.start + indent;
+                edits.push((start..start + hashes, "#".repeat(level)));
+            } else {
+                // setext heading, everything but the underline is the text
+                let text = source.trim_end().lines().collect::<Vec<_>>();
+                let text = text[..text.len().saturating_sub(1)].join(" ");
+                edits.push((range, format!("{} {}\n", "#".repeat(level), text.trim())));
+            }
+        }
+    }
+    apply_edits(content, edits)
+}
+
+/// Whether the first block of the content, ignoring HTML comments and other
+/// raw HTML, is a heading.
+pub fn starts_with_heading(content: &str) -> bool {
+    Parser::new_ext(content, options())
+        .find(|event| {
+            !matches!(
+                event,
+                Event::Start(Tag::HtmlBlock) | Event::End(TagEnd::HtmlBlock) | Event::Html(_)
+            )
+        })
+        .is_some_and(|event| matches!(event, Event::Start(Tag::Heading { .. })))
+}
+
+/// Replace every `<!-- docx:pagebreak -->` comment outside of code with the
+/// given marker paragraph.
+pub fn replace_page_break_comments(content: &str, marker: &str) -> String {
+    let mut edits = Vec::new();
+    for (event, range) in Parser::new_ext(content, options()).into_offset_iter() {
+        if let Event::Html(html) | Event::InlineHtml(html) = event {
+            if html.trim() == "<!-- docx:pagebreak -->" {
+                edits.push((range, format!("\n\n{}\n\n", marker)));
+            }
+        }
+    }
+    apply_edits(content, edits)
+}
+
+/// Replace fenced code blocks. `f` is given the info string and the code of
+/// each block, and returns the markdown to put in its place, or `None` to
+/// leave the block untouched.
+pub fn replace_code_blocks(
+    content: &str,
+    mut f: impl FnMut(&str, &str) -> Option<String>,
+) -> String {
+    let mut edits = Vec::new();
+    let mut current: Option<(Range<usize>, String, String)> = None;
+    for (event, range) in Parser::new_ext(content, options()).into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+                current = Some((range, info.to_string(), String::new()))
+            }
+            Event::Text(text) => {
+                if let Some((_, _, code)) = current.as_mut() {
+                    code.push_str(&text);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                let Some((range, info, code)) = current.take() else {
+                    continue;
+                };
+                if let Some(markdown) = f(&info, &code) {
+                    edits.push((range, format!("{}\n\n", markdown)));
+                }
+            }
+            _ => {}
+        }
+    }
+    apply_edits(content, edits)
+}
+
+/// The anchor mdBook gives each heading in the content, in order. An explicit
+/// `{#id}` wins, otherwise the id is derived from the heading text.
+pub fn heading_anchors(content: &str) -> Vec<String> {
+    let mut anchors = Vec::new();
+    let mut counter = HashMap::new();
+    let mut heading: Option<(Option<String>, String)> = None;
+    for event in Parser::new_ext(content, options()) {
+        match event {
+            Event::Start(Tag::Heading { id, .. }) => {
+                heading = Some((id.map(|id| id.to_string()), String::new()))
+            }
+            Event::Text(text) | Event::Code(text) => {
+                if let Some((_, ref mut name)) = heading {
+                    name.push_str(&text);
+                }
+            }
+            Event::End(TagEnd::Heading(_)) => {
+                if let Some((id, name)) = heading.take() {
+                    anchors.push(id.unwrap_or_else(|| unique_id_from_content(&name, &mut counter)));
+                }
+            }
+            _ => {}
+        }
+    }
+    anchors
+}
+
+/// Give the headings of the content, in order, the provided pandoc
+/// identifiers. Any existing attribute block on a heading is replaced.
+pub fn set_heading_ids(content: &str, ids: &[String]) -> String {
+    let mut edits = Vec::new();
+    let mut ids = ids.iter();
+    for (event, range) in Parser::new_ext(content, options()).into_offset_iter() {
+        if let Event::Start(Tag::Heading {
+            id, classes, attrs, ..
+        }) = event
+        {
+            let Some(new_id) = ids.next() else { break };
+            let source = &content[range.clone()];
+            let line = source.lines().next().unwrap_or_default().trim_end();
+            let end = range.start + line.len();
+            let has_attributes = id.is_some() || !classes.is_empty() || !attrs.is_empty();
+            let start = match line.rfind('{') {
+                Some(open) if has_attributes => end - (line.len() - open),
+                _ => end,
+            };
+            let separator = if start == end { " " } else { "" };
+            edits.push((start..end, format!("{}{{#{}}}", separator, new_id)));
+        }
+    }
+    apply_edits(content, edits)
+}
+
+/// What a link destination is rewritten to.
+pub enum Rewrite {
+    /// leave the link untouched
+    Keep,
+    /// point the link at a new destination
+    Destination(String),
+    /// drop the link, keeping its text
+    Text,
+}
+
+// a link found in the content and the source span of its text
+struct LinkSpan {
+    range: Range<usize>,
+    text: Option<Range<usize>>,
+    dest: String,
+    title: String,
+    link_type: LinkType,
+}
+
+/// Rewrite the destination of every inline and reference link in the
+/// content. Reference links that change are rewritten as inline links.
+pub fn rewrite_links(content: &str, mut f: impl FnMut(&str) -> Rewrite) -> String {
+    let mut edits = Vec::new();
+    let mut current: Option<LinkSpan> = None;
+    for (event, range) in Parser::new_ext(content, options()).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Link {
+                link_type,
+                dest_url,
+                title,
+                ..
+            }) => {
+                current = Some(LinkSpan {
+                    range,
+                    text: None,
+                    dest: dest_url.to_string(),
+                    title: title.to_string(),
+                    link_type,
+                })
+            }
+            Event::End(TagEnd::Link) => {
+                let Some(link) = current.take() else { continue };
+                if matches!(link.link_type, LinkType::Autolink | LinkType::Email) {
+                    continue;
+                }
+                let text = link.text.map_or("", |r| &content[r]);
+                match f(&link.dest) {
+                    Rewrite::Keep => {}
+                    Rewrite::Text => edits.push((link.range, text.to_string())),
+                    Rewrite::Destination(dest) => edits.push((
+                        link.range,
+                        format!(
+                            "[{}]({}{})",
+                            text,
+                            inline_destination(&dest),
+                            inline_title(&link.title)
+                        ),
+                    )),
+                }
+            }
+            _ => {
+                if let Some(link) = current.as_mut() {
+                    link.text = Some(match link.text.take() {
+                        Some(text) => text.start.min(range.start)..text.end.max(range.end),
+                        None => range,
+                    });
+                }
+            }
+        }
+    }
+    apply_edits(content, edits)
+}
+
+/// An inline image, with any brackets in its alt text escaped.
+pub fn image(alt: &str, dest: &str) -> String {
+    let alt = alt.replace('[', "\\[").replace(']', "\\]");
+    format!("![{}]({})", alt, inline_destination(dest))
+}
+
+// a destination safe to place inside `(...)`
+fn inline_destination(dest: &str) -> String {
+    if dest.contains(|c: char| c.is_whitespace() || c == '(' || c == ')') {
+        format!("<{}>", dest)
+    } else {
+        dest.to_string()
+    }
+}
+
+// a link title safe to place inside `(...)`, including its leading space
+fn inline_title(title: &str) -> String {
+    if title.is_empty() {
+        String::new()
+    } else {
+        format!(" \"{}\"", title.replace('"', "\\\""))
+    }
+}
+
+/// The destination of every inline and reference link in the content.
+pub fn links(content: &str) -> Vec<String> {
+    Parser::new_ext(content, options())
+        .filter_map(|event| match event {
+            Event::Start(Tag::Link {
+                link_type,
+                dest_url,
+                ..
+            }) if !matches!(link_type, LinkType::Autolink | LinkType::Email) => {
+                Some(dest_url.to_string())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Rewrite the source of every image in the content, including raw `<img>`
+/// tags. `f` returns the new source, or `None` to leave an image untouched.
+pub fn rewrite_images(content: &str, mut f: impl FnMut(&str) -> Option<String>) -> String {
+    let mut edits = Vec::new();
+    let mut current: Option<LinkSpan> = None;
+    for (event, range) in Parser::new_ext(content, options()).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Image {
+                link_type,
+                dest_url,
+                title,
+                ..
+            }) => {
+                current = Some(LinkSpan {
+                    range,
+                    text: None,
+                    dest: dest_url.to_string(),
+                    title: title.to_string(),
+                    link_type,
+                })
+            }
+            Event::End(TagEnd::Image) => {
+                let Some(image) = current.take() else {
+                    continue;
+                };
+                if let Some(dest) = f(&image.dest) {
+                    let alt = image.text.map_or("", |r| &content[r]);
+                    edits.push((
+                        image.range,
+                        format!(
+                            "![{}]({}{})",
+                            alt,
+                            inline_destination(&dest),
+                            inline_title(&image.title)
+                        ),
+                    ));
+                }
+            }
+            Event::Html(html) | Event::InlineHtml(html) if current.is_none() => {
+                // the offsets of the tag only hold when the event is the source text as is
+                if content[range.clone()] != *html {
+                    continue;
+                }
+                for c in IMG_SRC.captures_iter(&html) {
+                    let Some(src) = c.get(1).or_else(|| c.get(2)) else {
+                        continue;
+                    };
+                    if let Some(dest) = f(src.as_str()) {
+                        let start = range.start + src.start();
+                        edits.push((start..range.start + src.end(), dest));
+                    }
+                }
+            }
+            _ => {
+                if let Some(image) = current.as_mut() {
+                    image.text = Some(match image.text.take() {
+                        Some(text) => text.start.min(range.start)..text.end.max(range.end),
+                        None => range,
+                    });
+                }
+            }
+        }
+    }
+    apply_edits(content, edits)
+}
+
+/// The source of every image in the content, including raw `<img>` tags.
+pub fn images(content: &str) -> Vec<String> {
+    let mut images = Vec::new();
+    for event in Parser::new_ext(content, options()) {
+        match event {
+            Event::Start(Tag::Image { dest_url, .. }) => images.push(dest_url.to_string()),
+            Event::Html(html) | Event::InlineHtml(html) => images.extend(
+                IMG_SRC
+                    .captures_iter(&html)
+                    .filter_map(|c| c.get(1).or_else(|| c.get(2)))
+                    .map(|m| m.as_str().to_string()),
+            ),
+            _ => {}
+        }
+    }
+    images
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn heading_ids() {
+        let content =
+            "# Intro\n\nText\n\n## Closed ##\n\nSetext\n======\n\nTwo line\nsetext\n---\n";
+        assert_eq!(
+            set_heading_ids(content, &ids(&["a", "b", "c", "d"])),
+            "# Intro {#a}\n\nText\n\n## Closed ## {#b}\n\nSetext {#c}\n======\n\nTwo line {#d}\nsetext\n---\n"
+        );
+    }
+
+    #[test]
+    fn heading_ids_replace_attributes() {
+        // mdBook reads any trailing brace block as attributes, not text
+        assert_eq!(
+            set_heading_ids(
+                "# Intro {#intro .unnumbered}\n\n## Plain {braces}\n\n## Spaced {#x}  \n",
+                &ids(&["a", "b", "c"])
+            ),
+            "# Intro {#a}\n\n## Plain {#b}\n\n## Spaced {#c}  \n"
+        );
+    }
+
+    #[test]
+    fn heading_ids_skip_code() {
+        assert_eq!(
+            set_heading_ids("```\n# not a heading\n```\n\n# Heading\n", &ids(&["a"])),
+            "```\n# not a heading\n```\n\n# Heading {#a}\n"
+        );
+        // headings beyond the ids given are left alone
+        assert_eq!(
+            set_heading_ids("# One\n\n# Two\n", &ids(&["a"])),
+            "# One {#a}\n\n# Two\n"
+        );
+    }
+
+    fn to_bookmark(dest: &str) -> Rewrite {
+        match dest {
+            "two.md" => Rewrite::Destination("#_ch2".to_string()),
+            "gone.md" => Rewrite::Text,
+            _ => Rewrite::Keep,
+        }
+    }
+
+    #[test]
+    fn links() {
+        assert_eq!(
+            rewrite_links(
+                "See [two](two.md \"Two\"), [gone](gone.md) and [web](https://x.org).",
+                to_bookmark
+            ),
+            "See [two](#_ch2 \"Two\"), gone and [web](https://x.org)."
+        );
+    }
+
+    #[test]
+    fn reference_links() {
+        let content =
+            "See [two][t], [Two] and [kept][k].\n\n[t]: two.md\n[two]: two.md\n[k]: other.md\n";
+        assert_eq!(
+            rewrite_links(content, to_bookmark),
+            "See [two](#_ch2), [Two](#_ch2) and [kept][k].\n\n[t]: two.md\n[two]: two.md\n[k]: other.md\n"
+        );
+    }
+
+    #[test]
+    fn links_wrapping_images() {
+        let content = "[![pic](img/x.png)](two.md)";
+        let linked = rewrite_links(content, to_bookmark);
+        assert_eq!(linked, "[![pic](img/x.png)](#_ch2)");
+        assert_eq!(
+            rewrite_images(&linked, |dest| Some(format!("ch/{}", dest))),
+            "[![pic](ch/img/x.png)](#_ch2)"
+        );
+    }
+
+    #[test]
+    fn images() {
+        let content =
+            "![a [b]](x.png \"T\") ![kept](https://x.org/y.png) ![ref][r]\n\n[r]: y.png\n";
+        let rewritten = rewrite_images(content, |dest| {
+            (!dest.starts_with("https:")).then(|| format!("ch/{}", dest))
+        });
+        assert_eq!(
+            rewritten,
+            "![a [b]](ch/x.png \"T\") ![kept](https://x.org/y.png) ![ref](ch/y.png)\n\n[r]: y.png\n"
+        );
+        assert_eq!(
+            rewrite_images("![a](x.png)", |_| Some("my dir/x.png".to_string())),
+            "![a](<my dir/x.png>)"
+        );
+    }
+
+    #[test]
+    fn raw_images() {
+        let content = "<div>\n<img src=\"x.png\" alt=\"x\">\n<IMG SRC='y.png'>\n</div>\n\nInline <img src=\"z.png\"> tag.\n\n```\n<img src=\"code.png\">\n```\n";
+        assert_eq!(
+            rewrite_images(content, |dest| Some(format!("ch/{}", dest))),
+            "<div>\n<img src=\"ch/x.png\" alt=\"x\">\n<IMG SRC='ch/y.png'>\n</div>\n\nInline <img src=\"ch/z.png\"> tag.\n\n```\n<img src=\"code.png\">\n```\n"
+        );
+    }
+
+    #[test]
+    fn shifted_headings() {
+        let content = "# One\n\n## Two ##\n\nSetext\n======\n\n##### Five\n\n    # code\n";
+        assert_eq!(
+            shift_headings(content, 2),
+            "### One\n\n#### Two ##\n\n### Setext\n\n###### Five\n\n    # code\n"
+        );
+        assert_eq!(shift_headings(content, 0), content);
+    }
+}
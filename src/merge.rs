@@ -0,0 +1,1886 @@
+//! Merging of .docx packages, used to place inserted documents into the one
+//! pandoc generates.
+//!
+//! The body of the source document is copied into the target along with
+//! everything it refers to: relationships are given new ids, media, headers
+//! and footers are copied under unused part names, list definitions are
+//! renumbered, and styles the target lacks or defines differently are added.
+//! The source's final `w:sectPr` travels with its content, so it keeps its
+//! own page setup, headers and footers as a separate Word section.
+
+use crate::docx::{
+    self, element_end, find_body_sect_pr, find_paragraph, Package, DOCUMENT_XML, SETTINGS_XML,
+};
+use anyhow::{bail, Context, Result};
+use regex::{Captures, Regex};
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::LazyLock,
+};
+
+pub const CONTENT_TYPES_XML: &str = "[Content_Types].xml";
+pub const DOCUMENT_RELS: &str = "word/_rels/document.xml.rels";
+const NUMBERING_XML: &str = "word/numbering.xml";
+const STYLES_XML: &str = "word/styles.xml";
+const EMPTY_RELS: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"></Relationships>"#;
+
+pub const RELATIONSHIP_TYPES: &str =
+    "http://schemas.openxmlformats.org/officeDocument/2006/relationships";
+
+// relationships belonging to the document as a whole rather than its content,
+// which the target already has its own of
+const DOCUMENT_LEVEL: [&str; 16] = [
+    "styles",
+    "stylesWithEffects",
+    "numbering",
+    "settings",
+    "webSettings",
+    "fontTable",
+    "theme",
+    "customXml",
+    "glossaryDocument",
+    "footnotes",
+    "endnotes",
+    "comments",
+    "commentsExtended",
+    "commentsIds",
+    "commentsExtensible",
+    "people",
+];
+
+static RELATIONSHIP: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<Relationship\b[^>]*>").unwrap());
+static ATTRIBUTE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"([\w:]+)="([^"]*)""#).unwrap());
+static RELATIONSHIP_REF: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(\br:\w+=")([^"]*)(")"#).unwrap());
+static NUM_ID_REF: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(<w:numId\s+w:val=")(\d+)(")"#).unwrap());
+static STYLE_REF: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(<w:(?:pStyle|rStyle|tblStyle|basedOn|next|link)\s+w:val=")([^"]*)(")"#).unwrap()
+});
+static DOC_PR_ID: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(<wp:docPr\b[^>]*?\bid=")(\d+)(")"#).unwrap());
+static ABSTRACT_NUM: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<w:abstractNum\b.*?</w:abstractNum>").unwrap());
+static NUM: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s)<w:num\b.*?</w:num>").unwrap());
+static ABSTRACT_NUM_ID: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(w:abstractNumId(?:="|\s+w:val="))(\d+)(")"#).unwrap());
+static NUM_ID: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(<w:num\b[^>]*?\bw:numId=")(\d+)(")"#).unwrap());
+static STYLE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<w:style\b.*?</w:style>").unwrap());
+static STYLE_ID: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"\bw:styleId="([^"]*)""#).unwrap());
+static STYLE_NAME: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(<w:name\s+w:val=")([^"]*)(")"#).unwrap());
+static HEADER_FOOTER_REF: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"<w:(header|footer)Reference\b[^>]*?\bw:type="(\w+)""#).unwrap());
+static CONTENT_TYPE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<(?:Override|Default)\b[^>]*>").unwrap());
+static NAMESPACE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"\sxmlns:(\w+)="[^"]*""#).unwrap());
+static IGNORABLE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"\bmc:Ignorable="([^"]*)""#).unwrap());
+static STYLE_TYPE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"^<w:style\b[^>]*?\bw:type="(\w+)""#).unwrap());
+static STYLE_DEFAULT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"^<w:style\b[^>]*?\bw:default="1""#).unwrap());
+static DOC_DEFAULTS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<w:docDefaults\b(?:[^>]*/>|.*?</w:docDefaults>)").unwrap());
+static DEFAULT_PPR: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<w:pPr\b(?:[^>]*/>|.*?</w:pPr>)").unwrap());
+static DEFAULT_RPR: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<w:rPr\b(?:[^>]*/>|.*?</w:rPr>)").unwrap());
+static STYLE_NAME_END: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<w:name\b[^>]*/>(?:<w:aliases\b[^>]*/>)?").unwrap());
+static STYLED_ELEMENT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<w:(p|r|tbl)\b[^>]*?(/?)>").unwrap());
+static BASED_ON: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"<w:basedOn\s+w:val="([^"]*)""#).unwrap());
+static BOOKMARK_ID: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(<w:bookmark(?:Start|End)\b[^>]*?\bw:id=")(\d+)(")"#).unwrap());
+static BOOKMARK_NAME: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(<w:bookmarkStart\b[^>]*?\bw:name=")([^"]*)(")"#).unwrap());
+// the places a bookmark is referred to by name: hyperlinks and fields
+static BOOKMARK_REF: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(\bw:anchor="|\b(?:PAGEREF|NOTEREF|REF)\s+)([^\s"<\\]+)("|)"#).unwrap()
+});
+static RFONTS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<w:rFonts\b[^>]*?/>").unwrap());
+static THEME_FONT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"\bw:(?:asciiTheme|hAnsiTheme|eastAsiaTheme|cstheme)=""#).unwrap()
+});
+static MAJOR_FONT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<a:majorFont>.*?</a:majorFont>").unwrap());
+static MINOR_FONT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<a:minorFont>.*?</a:minorFont>").unwrap());
+static EVEN_AND_ODD_HEADERS: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"<w:evenAndOddHeaders\b(?:\s+w:val="(?:1|true|on)")?\s*/>"#).unwrap()
+});
+static SECT_PR: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<w:sectPr\b(?:[^>]*/>|.*?</w:sectPr>)").unwrap());
+static NOTE_ID: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(<w:(?:footnote|endnote)\b[^>]*?\bw:id=")(-?\d+)(")"#).unwrap());
+static NOTE_TYPE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"^<[^>]*?\bw:type="(\w+)""#).unwrap());
+static NOTE_REF: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"<w:(footnote|endnote|comment)Reference\b[^>]*/>|<w:commentRange(?:Start|End)\b[^>]*/>",
+    )
+    .unwrap()
+});
+
+/// Replace the marker paragraph in `target` with the content of `source`,
+/// placed in a section of its own. Returns warnings about content of the
+/// source that could not be carried over.
+pub fn insert(target: &mut Package, source: &Package, marker: &str) -> Result<Vec<String>> {
+    let body = Import::new(source, target).run()?;
+    let mut xml = target.xml(DOCUMENT_XML)?;
+    let range = find_marker(&xml, marker)?;
+
+    // the section the marker sits in ends at the next sectPr
+    let next = xml[range.end..].find("<w:sectPr").and_then(|at| {
+        let at = range.end + at;
+        Some(at..element_end(&xml, at, "w:sectPr")?)
+    });
+    let section = match &next {
+        Some(next) => xml[next.clone()].to_string(),
+        None => "<w:sectPr/>".to_string(),
+    };
+    let starts_section = xml[section_start(&xml, range.start)?..range.start]
+        .trim()
+        .is_empty();
+    let ends_document = match find_body_sect_pr(&xml) {
+        Some(body_sect_pr) => xml[range.end..body_sect_pr.start].trim().is_empty(),
+        None => xml[range.end..].trim_start().starts_with("</w:body>"),
+    };
+
+    // content ahead of the marker keeps the section it was in
+    let mut replacement = match starts_section {
+        true => String::new(),
+        false => section_break(&section),
+    };
+    replacement.push_str(&body.content);
+    if let Some(next) = next {
+        if ends_document {
+            // the inserted section is the last, so its properties become the body's
+            if let Some(sect_pr) = &body.sect_pr {
+                xml.replace_range(next, sect_pr);
+            }
+        } else {
+            replacement.push_str(&section_break(body.sect_pr.as_ref().unwrap_or(&section)));
+            // stop the content after the insert inheriting its headers and footers
+            if let Some(previous) = &body.sect_pr {
+                let sect_pr = blank_references(target, &xml[next.clone()], previous)?;
+                xml.replace_range(next, &sect_pr);
+            }
+        }
+    } else if let Some(sect_pr) = &body.sect_pr {
+        replacement.push_str(sect_pr);
+    }
+    xml.replace_range(range, &replacement);
+    target.set_part(DOCUMENT_XML, xml.into_bytes());
+    Ok(body.warnings)
+}
+
+/// Replace the marker paragraph in `target` with WordprocessingML body content.
+pub fn insert_raw(target: &mut Package, content: &str, marker: &str) -> Result<()> {
+    let mut xml = target.xml(DOCUMENT_XML)?;
+    let range = find_marker(&xml, marker)?;
+    xml.replace_range(range, content.trim());
+    target.set_part(DOCUMENT_XML, xml.into_bytes());
+    Ok(())
+}
+
+fn find_marker(xml: &str, marker: &str) -> Result<std::ops::Range<usize>> {
+    find_paragraph(xml, marker)
+        .with_context(|| format!("No paragraph holding {} was found in the document", marker))
+}
+
+// the start of the section containing `at`, just after the previous section break
+fn section_start(xml: &str, at: usize) -> Result<usize> {
+    let previous = [
+        xml[..at].rfind("</w:sectPr>"),
+        xml[..at].rfind("<w:sectPr/>"),
+    ]
+    .into_iter()
+    .flatten()
+    .max();
+    match previous {
+        Some(i) => Ok(i + xml[i..]
+            .find("</w:p>")
+            .map_or(0, |end| end + "</w:p>".len())),
+        None => body_start(xml),
+    }
+}
+
+// give a sectPr an empty header or footer for each one `previous` has and it
+// does not, as Word otherwise carries them over from the previous section
+fn blank_references(target: &mut Package, sect_pr: &str, previous: &str) -> Result<String> {
+    let defined: BTreeSet<(String, String)> = HEADER_FOOTER_REF
+        .captures_iter(sect_pr)
+        .map(|c| (c[1].to_string(), c[2].to_string()))
+        .collect();
+    let mut references = String::new();
+    for c in HEADER_FOOTER_REF.captures_iter(previous) {
+        let (kind, page) = (&c[1], &c[2]);
+        if defined.contains(&(kind.to_string(), page.to_string())) {
+            continue;
+        }
+        let element = match kind {
+            "header" => "w:hdr",
+            _ => "w:ftr",
+        };
+        let name = unused_name(target, &format!("word/{}-blank.xml", kind));
+        let xml = format!(
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?><{} xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main"><w:p/></{}>"#,
+            element, element
+        );
+        target.set_part(&name, xml.into_bytes());
+
+        let mut types = target.xml(CONTENT_TYPES_XML)?;
+        let content_type = format!(
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.{}+xml",
+            kind
+        );
+        add_content_type(&mut types, &name, &content_type);
+        target.set_part(CONTENT_TYPES_XML, types.into_bytes());
+
+        let mut rels = target.xml(DOCUMENT_RELS)?;
+        let kind_uri = format!("{}/{}", RELATIONSHIP_TYPES, kind);
+        let id = add_relationship(&mut rels, &kind_uri, &relative("word", &name), false);
+        target.set_part(DOCUMENT_RELS, rels.into_bytes());
+
+        references.push_str(&format!(
+            r#"<w:{}Reference w:type="{}" r:id="{}"/>"#,
+            kind, page, id
+        ));
+    }
+
+    // references are the first children of a sectPr
+    let mut sect_pr = sect_pr.to_string();
+    if sect_pr.ends_with("/>") {
+        let open = sect_pr.trim_end_matches("/>").trim_end().to_string();
+        sect_pr = format!("{}></w:sectPr>", open);
+    }
+    let at = sect_pr.find('>').map_or(sect_pr.len(), |i| i + 1);
+    sect_pr.insert_str(at, &references);
+    Ok(sect_pr)
+}
+
+/// The content of a source document's body, rewritten for the target.
+struct Body {
+    content: String,
+    sect_pr: Option<String>,
+    warnings: Vec<String>,
+}
+
+/// The state of copying one package's content into another.
+struct Import<'a> {
+    source: &'a Package,
+    target: &'a mut Package,
+    // source part names to their names in the target
+    parts: HashMap<String, String>,
+    num_ids: HashMap<String, String>,
+    styles: HashMap<String, String>,
+    // the style given to paragraphs, runs and tables without one, where the
+    // source's default style is not the target's
+    default_styles: HashMap<&'static str, String>,
+    // the source's theme fonts, where they are not the target's
+    theme_fonts: Option<ThemeFonts>,
+}
+
+impl<'a> Import<'a> {
+    fn new(source: &'a Package, target: &'a mut Package) -> Self {
+        Self {
+            source,
+            target,
+            parts: HashMap::new(),
+            num_ids: HashMap::new(),
+            styles: HashMap::new(),
+            default_styles: HashMap::new(),
+            theme_fonts: None,
+        }
+    }
+
+    fn run(mut self) -> Result<Body> {
+        let source_fonts = theme_fonts(self.source)?;
+        if source_fonts != theme_fonts(self.target)? {
+            self.theme_fonts = source_fonts;
+        }
+        self.merge_numbering()?;
+        self.merge_styles()?;
+        self.merge_settings()?;
+
+        // give the document's relationships new ids in the target, copying
+        // the parts they point to
+        let mut target_rels = self.target.xml(DOCUMENT_RELS)?;
+        let mut ids = HashMap::new();
+        for rel in relationships(&self.source.xml(DOCUMENT_RELS)?) {
+            let kind = rel.kind.rsplit('/').next().unwrap_or_default();
+            if DOCUMENT_LEVEL.contains(&kind) {
+                continue;
+            }
+            let target = if rel.external {
+                rel.target.clone()
+            } else {
+                let name = self.copy_part(&resolve("word", &rel.target))?;
+                relative("word", &name)
+            };
+            let id = add_relationship(&mut target_rels, &rel.kind, &target, rel.external);
+            ids.insert(rel.id, id);
+        }
+        self.target
+            .set_part(DOCUMENT_RELS, target_rels.into_bytes());
+
+        let xml = self.source.xml(DOCUMENT_XML)?;
+        let (xml, copied) = self.merge_notes(&xml)?;
+        let (xml, warnings) = remove_note_references(&xml, &copied);
+        let xml = replace_values(&RELATIONSHIP_REF, &xml, &ids);
+        let xml = self.rewrite(&xml);
+        let xml = self.renumber_drawings(&xml)?;
+        let xml = self.renumber_bookmarks(&xml)?;
+        self.merge_namespaces(DOCUMENT_XML, &xml)?;
+
+        let start = body_start(&xml)?;
+        let end = xml.rfind("</w:body>").context("The document has no body")?;
+        let (content, sect_pr) = match find_body_sect_pr(&xml) {
+            Some(range) => (
+                xml[start..range.start].to_string(),
+                Some(xml[range].to_string()),
+            ),
+            None => (xml[start..end].to_string(), None),
+        };
+        Ok(Body {
+            content,
+            sect_pr,
+            warnings,
+        })
+    }
+
+    // list and style references in content copied from the source
+    fn rewrite(&self, xml: &str) -> String {
+        let xml = replace_values(&NUM_ID_REF, xml, &self.num_ids);
+        let xml = replace_values(&STYLE_REF, &xml, &self.styles);
+        let xml = self.set_default_styles(&xml);
+        match &self.theme_fonts {
+            Some(fonts) => fonts.resolve(&xml),
+            None => xml,
+        }
+    }
+
+    // give paragraphs, runs and tables without a style the source's default
+    // style, as they would otherwise take the target's
+    fn set_default_styles(&self, xml: &str) -> String {
+        if self.default_styles.is_empty() {
+            return xml.to_string();
+        }
+        let mut out = String::with_capacity(xml.len());
+        let mut last = 0;
+        for c in STYLED_ELEMENT.captures_iter(xml) {
+            let Some(style) = self.default_styles.get(&c[1]) else {
+                continue;
+            };
+            let (properties, reference_name) = match &c[1] {
+                "p" => ("w:pPr", "w:pStyle"),
+                "r" => ("w:rPr", "w:rStyle"),
+                _ => ("w:tblPr", "w:tblStyle"),
+            };
+            let reference = format!(r#"<{} w:val="{}"/>"#, reference_name, style);
+            let with_reference = format!("<{}>{}</{}>", properties, reference, properties);
+            let element = c.get(0).unwrap();
+            out.push_str(&xml[last..element.start()]);
+            last = element.end();
+
+            if &c[2] == "/" {
+                let open = element.as_str().trim_end_matches("/>").trim_end();
+                out.push_str(&format!("{}>{}</w:{}>", open, with_reference, &c[1]));
+                continue;
+            }
+            out.push_str(element.as_str());
+            // the properties, if any, are the element's first child and the
+            // style reference is theirs
+            let rest = &xml[last..];
+            let space = rest.len() - rest.trim_start().len();
+            let rest = &rest[space..];
+            let has_properties = rest
+                .strip_prefix(&format!("<{}", properties))
+                .is_some_and(|r| r.starts_with(['>', '/', ' ']));
+            if !has_properties {
+                out.push_str(&with_reference);
+                continue;
+            }
+            let Some(tag_end) = rest.find('>').map(|i| i + 1) else {
+                continue;
+            };
+            out.push_str(&xml[last..last + space]);
+            if rest[..tag_end].ends_with("/>") {
+                out.push_str(&with_reference);
+            } else {
+                out.push_str(&rest[..tag_end]);
+                let styled = rest[tag_end..]
+                    .trim_start()
+                    .strip_prefix(&format!("<{}", reference_name))
+                    .is_some_and(|r| r.starts_with([' ', '/']));
+                if !styled {
+                    out.push_str(&reference);
+                }
+            }
+            last += space + tag_end;
+        }
+        out.push_str(&xml[last..]);
+        out
+    }
+
+    /// Copy a part and the parts it refers to, returning its name in the target.
+    fn copy_part(&mut self, name: &str) -> Result<String> {
+        if let Some(copied) = self.parts.get(name) {
+            return Ok(copied.clone());
+        }
+        let data = match self.source.part(name) {
+            Some(data) => data.to_vec(),
+            None => bail!("The part {} referenced by the document is missing", name),
+        };
+        let new_name = unused_name(self.target, name);
+        self.parts.insert(name.to_string(), new_name.clone());
+
+        let data = match name.ends_with(".xml") {
+            true => self.rewrite(&String::from_utf8(data)?).into_bytes(),
+            false => data,
+        };
+
+        // a part's own relationships keep their ids, only their targets move
+        if let Some(rels) = self.source.part(&rels_name(name)) {
+            let mut rels = String::from_utf8(rels.to_vec())?;
+            let dir = parent(name);
+            for rel in relationships(&rels.clone())
+                .into_iter()
+                .filter(|r| !r.external)
+            {
+                let copied = self.copy_part(&resolve(dir, &rel.target))?;
+                let from = format!(r#"Target="{}""#, rel.target);
+                let to = format!(r#"Target="{}""#, relative(parent(&new_name), &copied));
+                rels = rels.replace(&from, &to);
+            }
+            self.target
+                .set_part(&rels_name(&new_name), rels.into_bytes());
+        }
+
+        if let Some(content_type) = content_type(&self.source.xml(CONTENT_TYPES_XML)?, name) {
+            let mut types = self.target.xml(CONTENT_TYPES_XML)?;
+            add_content_type(&mut types, &new_name, &content_type);
+            self.target.set_part(CONTENT_TYPES_XML, types.into_bytes());
+        }
+        self.target.set_part(&new_name, data);
+        Ok(new_name)
+    }
+
+    // copy a document-level part the target does not have at all
+    fn copy_document_part(&mut self, name: &str, kind: &str) -> Result<()> {
+        self.copy_part(name)?;
+        let mut rels = self.target.xml(DOCUMENT_RELS)?;
+        add_relationship(
+            &mut rels,
+            &format!("{}/{}", RELATIONSHIP_TYPES, kind),
+            &relative("word", name),
+            false,
+        );
+        self.target.set_part(DOCUMENT_RELS, rels.into_bytes());
+        Ok(())
+    }
+
+    /// Add the source's list definitions to the target under unused ids.
+    fn merge_numbering(&mut self) -> Result<()> {
+        let source = match self.source.part(NUMBERING_XML) {
+            Some(_) => self.source.xml(NUMBERING_XML)?,
+            None => return Ok(()),
+        };
+        if self.target.part(NUMBERING_XML).is_none() {
+            return self.copy_document_part(NUMBERING_XML, "numbering");
+        }
+        let mut target = self.target.xml(NUMBERING_XML)?;
+
+        let next_abstract = max_value(&ABSTRACT_NUM_ID, &target) + 1;
+        let abstract_ids: HashMap<String, String> = ABSTRACT_NUM
+            .find_iter(&source)
+            .filter_map(|m| ABSTRACT_NUM_ID.captures(m.as_str()))
+            .enumerate()
+            .map(|(i, c)| (c[2].to_string(), (next_abstract + i as u64).to_string()))
+            .collect();
+        let next_num = max_value(&NUM_ID, &target) + 1;
+        self.num_ids = NUM
+            .find_iter(&source)
+            .filter_map(|m| NUM_ID.captures(m.as_str()))
+            .enumerate()
+            .map(|(i, c)| (c[2].to_string(), (next_num + i as u64).to_string()))
+            .collect();
+
+        let abstract_nums: String = ABSTRACT_NUM
+            .find_iter(&source)
+            .map(|m| replace_values(&ABSTRACT_NUM_ID, m.as_str(), &abstract_ids))
+            .collect();
+        let nums: String = NUM
+            .find_iter(&source)
+            .map(|m| replace_values(&ABSTRACT_NUM_ID, m.as_str(), &abstract_ids))
+            .map(|num| replace_values(&NUM_ID, &num, &self.num_ids))
+            .collect();
+
+        // every abstractNum comes before the first num
+        let end = target
+            .rfind("</w:numbering>")
+            .context("Invalid numbering part")?;
+        target.insert_str(end, &nums);
+        let at = match ABSTRACT_NUM.find_iter(&target).last() {
+            Some(m) => m.end(),
+            None => target
+                .find("<w:num ")
+                .or(target.find("<w:num>"))
+                .unwrap_or(end),
+        };
+        target.insert_str(at, &abstract_nums);
+        self.target.set_part(NUMBERING_XML, target.into_bytes());
+        Ok(())
+    }
+
+    /// Add the source's styles to the target. A style the target defines
+    /// differently is added under a new id so the source content keeps its look.
+    fn merge_styles(&mut self) -> Result<()> {
+        let source = match self.source.part(STYLES_XML) {
+            Some(_) => self.source.xml(STYLES_XML)?,
+            None => return Ok(()),
+        };
+        if self.target.part(STYLES_XML).is_none() {
+            return self.copy_document_part(STYLES_XML, "styles");
+        }
+        let mut target = self.target.xml(STYLES_XML)?;
+        let existing: HashMap<String, String> = STYLE
+            .find_iter(&target)
+            .filter_map(|m| {
+                Some((
+                    STYLE_ID.captures(m.as_str())?[1].to_string(),
+                    m.as_str().to_string(),
+                ))
+            })
+            .collect();
+        let mut taken: BTreeSet<String> = existing.keys().cloned().collect();
+        let source_defaults = default_styles(&source);
+        let target_defaults = default_styles(&target);
+
+        // the document defaults sit beneath every style, so where they differ
+        // the source's default paragraph style is kept apart from the
+        // target's with the source's defaults folded into it
+        let doc_defaults = DOC_DEFAULTS.find(&source).map(|m| m.as_str());
+        let fold_doc_defaults = match doc_defaults {
+            Some(defaults) => {
+                DOC_DEFAULTS.find(&target).map(|m| m.as_str().trim()) != Some(defaults.trim())
+                    || self.depends_on_theme(defaults)
+            }
+            None => false,
+        };
+        let default_paragraph = source_defaults
+            .get("paragraph")
+            .filter(|_| fold_doc_defaults);
+
+        let source_styles: Vec<(String, &str)> = STYLE
+            .find_iter(&source)
+            .filter_map(|m| Some((STYLE_ID.captures(m.as_str())?[1].to_string(), m.as_str())))
+            .collect();
+        // a style the target also defines looks different when its definition
+        // differs, when it uses theme fonts of a different theme, or when a
+        // style it is based on looks different
+        let mut conflicts: BTreeSet<&str> = source_styles
+            .iter()
+            .filter(|(id, style)| {
+                existing.get(id).is_some_and(|defined| {
+                    defined.trim() != style.trim()
+                        || self.depends_on_theme(style)
+                        || default_paragraph == Some(id)
+                })
+            })
+            .map(|(id, _)| id.as_str())
+            .collect();
+        loop {
+            let inherited: Vec<&str> = source_styles
+                .iter()
+                .filter(|(id, style)| {
+                    existing.contains_key(id)
+                        && !conflicts.contains(id.as_str())
+                        && BASED_ON
+                            .captures(style)
+                            .is_some_and(|c| conflicts.contains(&c[1]))
+                })
+                .map(|(id, _)| id.as_str())
+                .collect();
+            if inherited.is_empty() {
+                break;
+            }
+            conflicts.extend(inherited);
+        }
+
+        let mut added = Vec::new();
+        for (id, style) in &source_styles {
+            if conflicts.contains(id.as_str()) {
+                let new_id = (2..)
+                    .map(|i| format!("{}-{}", id, i))
+                    .find(|i| !taken.contains(i))
+                    .unwrap();
+                taken.insert(new_id.clone());
+                self.styles.insert(id.clone(), new_id);
+                added.push((id.clone(), *style));
+            } else if !existing.contains_key(id) {
+                added.push((id.clone(), *style));
+            }
+        }
+
+        // a hidden style holding the source's document defaults, which its
+        // default paragraph style is based on
+        let defaults_style = match (default_paragraph, doc_defaults) {
+            (Some(_), Some(doc_defaults)) => {
+                let (id, name) = (1..)
+                    .map(|i| match i {
+                        1 => ("DocDefaults".to_string(), "Document Defaults".to_string()),
+                        i => (
+                            format!("DocDefaults-{}", i),
+                            format!("Document Defaults ({})", i),
+                        ),
+                    })
+                    .find(|(id, _)| !taken.contains(id))
+                    .unwrap();
+                taken.insert(id.clone());
+                let properties: String = [&DEFAULT_PPR, &DEFAULT_RPR]
+                    .into_iter()
+                    .filter_map(|regex| regex.find(doc_defaults))
+                    .map(|m| m.as_str())
+                    .collect();
+                let properties = match &self.theme_fonts {
+                    Some(fonts) => fonts.resolve(&properties),
+                    None => properties,
+                };
+                Some((
+                    id.clone(),
+                    format!(
+                        r#"<w:style w:type="paragraph" w:styleId="{}"><w:name w:val="{}"/><w:semiHidden/>{}</w:style>"#,
+                        id, name, properties
+                    ),
+                ))
+            }
+            _ => None,
+        };
+
+        // content without a style takes the default style of its type
+        for (kind, element) in [("paragraph", "p"), ("character", "r"), ("table", "tbl")] {
+            let Some(id) = source_defaults.get(kind) else {
+                continue;
+            };
+            let id = self.styles.get(id).unwrap_or(id);
+            if target_defaults.get(kind) != Some(id) {
+                self.default_styles.insert(element, id.clone());
+            }
+        }
+
+        let mut styles: String = added
+            .into_iter()
+            .map(|(id, style)| {
+                // the target's default styles stay the defaults
+                let mut style = self.rewrite(style).replacen(r#" w:default="1""#, "", 1);
+                if let Some(new_id) = self.styles.get(&id) {
+                    let suffix = &new_id[id.len()..];
+                    style = style.replacen(
+                        &format!(r#"w:styleId="{}""#, id),
+                        &format!(r#"w:styleId="{}""#, new_id),
+                        1,
+                    );
+                    style = STYLE_NAME
+                        .replace(&style, |c: &Captures| {
+                            format!("{}{} ({}){}", &c[1], &c[2], &suffix[1..], &c[3])
+                        })
+                        .into_owned();
+                }
+                if let Some((defaults_id, _)) = defaults_style.as_ref() {
+                    if default_paragraph == Some(&id) && !style.contains("<w:basedOn") {
+                        let based_on = format!(r#"<w:basedOn w:val="{}"/>"#, defaults_id);
+                        if let Some(m) = STYLE_NAME_END.find(&style) {
+                            style.insert_str(m.end(), &based_on);
+                        }
+                    }
+                }
+                style
+            })
+            .collect();
+        if let Some((_, defaults_style)) = defaults_style {
+            styles.insert_str(0, &defaults_style);
+        }
+        let end = target.rfind("</w:styles>").context("Invalid styles part")?;
+        target.insert_str(end, &styles);
+        self.target.set_part(STYLES_XML, target.into_bytes());
+        Ok(())
+    }
+
+    /// Copy the source's footnotes and endnotes into the target's under
+    /// unused ids, returning the document with its references to them
+    /// renumbered and the kinds of note that were copied.
+    fn merge_notes(&mut self, xml: &str) -> Result<(String, Vec<&'static str>)> {
+        let mut xml = xml.to_string();
+        let mut copied = Vec::new();
+        for kind in ["footnote", "endnote"] {
+            let reference = Regex::new(&format!(
+                r#"(<w:{}Reference\b[^>]*?\bw:id=")(-?\d+)(")"#,
+                kind
+            ))
+            .unwrap();
+            let relationship = format!("{}s", kind);
+            if !reference.is_match(&xml) {
+                continue;
+            }
+            let Some(source_name) = related_part(self.source, &relationship)? else {
+                continue;
+            };
+            copied.push(kind);
+            let Some(target_name) = related_part(self.target, &relationship)? else {
+                // the notes keep their ids in a target without any
+                self.copy_document_part(&source_name, &relationship)?;
+                continue;
+            };
+
+            // the relationships of the notes, such as their links and images
+            let target_rels_name = rels_name(&target_name);
+            let mut target_rels = match self.target.part(&target_rels_name) {
+                Some(_) => self.target.xml(&target_rels_name)?,
+                None => EMPTY_RELS.to_string(),
+            };
+            let mut rel_ids = HashMap::new();
+            if self.source.part(&rels_name(&source_name)).is_some() {
+                for rel in relationships(&self.source.xml(&rels_name(&source_name))?) {
+                    let target = if rel.external {
+                        rel.target.clone()
+                    } else {
+                        let name = self.copy_part(&resolve(parent(&source_name), &rel.target))?;
+                        relative(parent(&target_name), &name)
+                    };
+                    let id = add_relationship(&mut target_rels, &rel.kind, &target, rel.external);
+                    rel_ids.insert(rel.id, id);
+                }
+            }
+            if !rel_ids.is_empty() {
+                self.target
+                    .set_part(&target_rels_name, target_rels.into_bytes());
+            }
+
+            // separators and continuation notices are the target's own
+            let mut target_notes = self.target.xml(&target_name)?;
+            let source_notes = self.source.xml(&source_name)?;
+            let note =
+                Regex::new(&format!(r"(?s)<w:{0}\b[^>]*?(?:/>|>.*?</w:{0}>)", kind)).unwrap();
+            let mut next = max_value(&NOTE_ID, &target_notes) + 1;
+            let mut ids = HashMap::new();
+            let mut notes = String::new();
+            for m in note.find_iter(&source_notes) {
+                let special = NOTE_TYPE
+                    .captures(m.as_str())
+                    .is_some_and(|c| &c[1] != "normal");
+                let Some(c) = NOTE_ID.captures(m.as_str()).filter(|_| !special) else {
+                    continue;
+                };
+                let id = next.to_string();
+                next += 1;
+                notes.push_str(&NOTE_ID.replace(m.as_str(), |c: &Captures| {
+                    format!("{}{}{}", &c[1], id, &c[3])
+                }));
+                ids.insert(c[2].to_string(), id);
+            }
+            let notes = self.rewrite(&replace_values(&RELATIONSHIP_REF, &notes, &rel_ids));
+            let end = target_notes
+                .rfind(&format!("</w:{}s>", kind))
+                .with_context(|| format!("{} has no closing element", target_name))?;
+            target_notes.insert_str(end, &notes);
+            self.target
+                .set_part(&target_name, target_notes.into_bytes());
+            self.merge_namespaces(&target_name, &source_notes)?;
+
+            xml = replace_values(&reference, &xml, &ids);
+        }
+        Ok((xml, copied))
+    }
+
+    // whether the look of styles or defaults changes with the theme they are in
+    fn depends_on_theme(&self, xml: &str) -> bool {
+        self.theme_fonts.is_some() && THEME_FONT.is_match(xml)
+    }
+
+    /// Turn on different headers and footers for even pages when the source
+    /// uses them. The target's own sections keep their headers and footers
+    /// on even pages.
+    fn merge_settings(&mut self) -> Result<()> {
+        let uses_even = match self.source.part(SETTINGS_XML) {
+            Some(_) => EVEN_AND_ODD_HEADERS.is_match(&self.source.xml(SETTINGS_XML)?),
+            None => false,
+        };
+        if !uses_even || self.target.part(SETTINGS_XML).is_none() {
+            return Ok(());
+        }
+        if EVEN_AND_ODD_HEADERS.is_match(&self.target.xml(SETTINGS_XML)?) {
+            return Ok(());
+        }
+        docx::add_setting(self.target, "<w:evenAndOddHeaders/>")?;
+
+        let xml = self.target.xml(DOCUMENT_XML)?;
+        let xml = SECT_PR.replace_all(&xml, |c: &Captures| even_references(&c[0]));
+        self.target
+            .set_part(DOCUMENT_XML, xml.into_owned().into_bytes());
+        Ok(())
+    }
+
+    // bookmarks need ids unique across the document, and names that do not
+    // clash with the target's, such as the `_GoBack` bookmark Word adds
+    fn renumber_bookmarks(&self, xml: &str) -> Result<String> {
+        let target = self.target.xml(DOCUMENT_XML)?;
+        let mut next = BOOKMARK_ID
+            .captures_iter(&target)
+            .filter_map(|c| c[2].parse::<u64>().ok())
+            .max()
+            .map_or(0, |max| max + 1);
+        let mut ids = HashMap::new();
+        for c in BOOKMARK_ID.captures_iter(xml) {
+            ids.entry(c[2].to_string()).or_insert_with(|| {
+                next += 1;
+                (next - 1).to_string()
+            });
+        }
+
+        let mut taken: BTreeSet<String> = BOOKMARK_NAME
+            .captures_iter(&target)
+            .map(|c| c[2].to_string())
+            .collect();
+        let mut names = HashMap::new();
+        for c in BOOKMARK_NAME.captures_iter(xml) {
+            let name = &c[2];
+            if !taken.contains(name) {
+                continue;
+            }
+            let new_name = (2..)
+                .map(|i| format!("{}_{}", name, i))
+                .find(|n| !taken.contains(n))
+                .unwrap();
+            taken.insert(new_name.clone());
+            names.insert(name.to_string(), new_name);
+        }
+
+        let xml = replace_values(&BOOKMARK_ID, xml, &ids);
+        let xml = replace_values(&BOOKMARK_NAME, &xml, &names);
+        Ok(replace_values(&BOOKMARK_REF, &xml, &names))
+    }
+
+    // drawings need ids unique across the document
+    fn renumber_drawings(&self, xml: &str) -> Result<String> {
+        let mut next = max_value(&DOC_PR_ID, &self.target.xml(DOCUMENT_XML)?);
+        Ok(DOC_PR_ID
+            .replace_all(xml, |c: &Captures| {
+                next += 1;
+                format!("{}{}{}", &c[1], next, &c[3])
+            })
+            .into_owned())
+    }
+
+    // declare the namespaces the source content uses on the target's root
+    fn merge_namespaces(&mut self, name: &str, source: &str) -> Result<()> {
+        let mut target = self.target.xml(name)?;
+        let source_root = root_element(source)?;
+        let target_root = root_element(&target)?.to_string();
+        let declared: BTreeSet<&str> = NAMESPACE
+            .captures_iter(&target_root)
+            .map(|c| c.get(1).unwrap().as_str())
+            .collect();
+
+        let mut root = target_root.clone();
+        let insert_at = root.len() - 1;
+        let missing: String = NAMESPACE
+            .captures_iter(source_root)
+            .filter(|c| !declared.contains(&c[1]))
+            .map(|c| c[0].to_string())
+            .collect();
+        root.insert_str(insert_at, &missing);
+
+        // prefixes Word may ignore in the source may be ignored in the target
+        if let (Some(source), Some(target)) =
+            (IGNORABLE.captures(source_root), IGNORABLE.captures(&root))
+        {
+            let mut prefixes: Vec<&str> = target[1].split_whitespace().collect();
+            for prefix in source[1].split_whitespace() {
+                if !prefixes.contains(&prefix) {
+                    prefixes.push(prefix);
+                }
+            }
+            let ignorable = format!(r#"mc:Ignorable="{}""#, prefixes.join(" "));
+            root = IGNORABLE.replace(&root, ignorable.as_str()).into_owned();
+        }
+
+        target = target.replacen(&target_root, &root, 1);
+        self.target.set_part(name, target.into_bytes());
+        Ok(())
+    }
+}
+
+pub struct Relationship {
+    pub id: String,
+    pub kind: String,
+    pub target: String,
+    pub external: bool,
+}
+
+/// The relationships listed in a .rels part.
+pub fn relationships(rels: &str) -> Vec<Relationship> {
+    RELATIONSHIP
+        .find_iter(rels)
+        .map(|m| {
+            let attributes: HashMap<&str, &str> = ATTRIBUTE
+                .captures_iter(m.as_str())
+                .map(|c| (c.get(1).unwrap().as_str(), c.get(2).unwrap().as_str()))
+                .collect();
+            let get = |name| attributes.get(name).unwrap_or(&"").to_string();
+            Relationship {
+                id: get("Id"),
+                kind: get("Type"),
+                target: get("Target"),
+                external: attributes.get("TargetMode") == Some(&"External"),
+            }
+        })
+        .collect()
+}
+
+/// Add a relationship under an unused id, returning the id.
+pub fn add_relationship(rels: &mut String, kind: &str, target: &str, external: bool) -> String {
+    let taken: BTreeSet<String> = relationships(rels).into_iter().map(|r| r.id).collect();
+    let id = (1..)
+        .map(|i| format!("rId{}", i))
+        .find(|id| !taken.contains(id))
+        .unwrap();
+    let mode = if external {
+        r#" TargetMode="External""#
+    } else {
+        ""
+    };
+    let element = format!(
+        r#"<Relationship Id="{}" Type="{}" Target="{}"{}/>"#,
+        id, kind, target, mode
+    );
+    let end = rels.rfind("</Relationships>").unwrap_or(rels.len());
+    rels.insert_str(end, &element);
+    id
+}
+
+// the content type of a part, from its override or the default for its extension
+fn content_type(types: &str, name: &str) -> Option<String> {
+    let part_name = format!("/{}", name);
+    let extension = extension(name);
+    let entries: Vec<HashMap<&str, &str>> = CONTENT_TYPE
+        .find_iter(types)
+        .map(|m| {
+            ATTRIBUTE
+                .captures_iter(m.as_str())
+                .map(|c| (c.get(1).unwrap().as_str(), c.get(2).unwrap().as_str()))
+                .collect()
+        })
+        .collect();
+    let matching = |attribute, value: &str| {
+        entries.iter().find(|e| {
+            e.get(attribute)
+                .is_some_and(|v| v.eq_ignore_ascii_case(value))
+        })
+    };
+    matching("PartName", &part_name)
+        .or_else(|| matching("Extension", &extension))
+        .and_then(|e| e.get("ContentType"))
+        .map(|t| t.to_string())
+}
+
+/// Give a part a content type, unless it already has that type.
+pub fn add_content_type(types: &mut String, name: &str, content_type: &str) {
+    if self::content_type(types, name).as_deref() == Some(content_type) {
+        return;
+    }
+    let element = format!(
+        r#"<Override PartName="/{}" ContentType="{}"/>"#,
+        name, content_type
+    );
+    let end = types.rfind("</Types>").unwrap_or(types.len());
+    types.insert_str(end, &element);
+}
+
+// a sectPr that also uses its default header and footer on even pages,
+// unless it has ones of its own for them
+fn even_references(sect_pr: &str) -> String {
+    let mut sect_pr = sect_pr.to_string();
+    for kind in ["header", "footer"] {
+        let reference = Regex::new(&format!(
+            r#"<w:{}Reference\b[^>]*?\bw:type="(\w+)"[^>]*?/>"#,
+            kind
+        ))
+        .unwrap();
+        let references: Vec<(String, String)> = reference
+            .captures_iter(&sect_pr)
+            .map(|c| (c[1].to_string(), c[0].to_string()))
+            .collect();
+        if references.iter().any(|(page, _)| page == "even") {
+            continue;
+        }
+        if let Some((_, default)) = references.iter().find(|(page, _)| page == "default") {
+            let even = default.replace(r#"w:type="default""#, r#"w:type="even""#);
+            let at = sect_pr.find(default.as_str()).unwrap() + default.len();
+            sect_pr.insert_str(at, &even);
+        }
+    }
+    sect_pr
+}
+
+// the fonts of a theme for headings (major) and body text (minor), each as
+// its latin, east asian and complex script typeface
+#[derive(Debug, PartialEq)]
+struct ThemeFonts {
+    major: [String; 3],
+    minor: [String; 3],
+}
+
+// the name of the part the document relates to with the given type, such as
+// its theme, if the package has it
+fn related_part(package: &Package, kind: &str) -> Result<Option<String>> {
+    Ok(relationships(&package.xml(DOCUMENT_RELS)?)
+        .into_iter()
+        .find(|rel| rel.kind == format!("{}/{}", RELATIONSHIP_TYPES, kind) && !rel.external)
+        .map(|rel| resolve("word", &rel.target))
+        .filter(|name| package.part(name).is_some()))
+}
+
+// the fonts of the package's theme, if it has one
+fn theme_fonts(package: &Package) -> Result<Option<ThemeFonts>> {
+    let Some(theme) = related_part(package, "theme")? else {
+        return Ok(None);
+    };
+    let xml = package.xml(&theme)?;
+    let typefaces = |regex: &Regex| -> [String; 3] {
+        let fonts = regex.find(&xml).map_or("", |m| m.as_str());
+        ["latin", "ea", "cs"].map(|script| {
+            Regex::new(&format!(r#"<a:{}\b[^>]*?\btypeface="([^"]*)""#, script))
+                .unwrap()
+                .captures(fonts)
+                .map_or(String::new(), |c| c[1].to_string())
+        })
+    };
+    Ok(Some(ThemeFonts {
+        major: typefaces(&MAJOR_FONT),
+        minor: typefaces(&MINOR_FONT),
+    }))
+}
+
+impl ThemeFonts {
+    // the typeface a theme font reference such as `minorHAnsi` stands for
+    fn typeface(&self, reference: &str) -> Option<&str> {
+        let (fonts, script) = match reference.strip_prefix("major") {
+            Some(script) => (&self.major, script),
+            None => (&self.minor, reference.strip_prefix("minor")?),
+        };
+        let font = match script {
+            "Ascii" | "HAnsi" => &fonts[0],
+            "EastAsia" => &fonts[1],
+            "Bidi" => &fonts[2],
+            _ => return None,
+        };
+        Some(font.as_str()).filter(|f| !f.is_empty())
+    }
+
+    // replace theme font references with the typefaces they stand for, as
+    // the target's theme would otherwise supply its own
+    fn resolve(&self, xml: &str) -> String {
+        const ATTRIBUTES: [(&str, &str); 4] = [
+            ("w:asciiTheme", "w:ascii"),
+            ("w:hAnsiTheme", "w:hAnsi"),
+            ("w:eastAsiaTheme", "w:eastAsia"),
+            ("w:cstheme", "w:cs"),
+        ];
+        RFONTS
+            .replace_all(xml, |c: &Captures| {
+                let mut attributes: Vec<(String, String)> = ATTRIBUTE
+                    .captures_iter(&c[0])
+                    .map(|a| (a[1].to_string(), a[2].to_string()))
+                    .collect();
+                for (theme, font) in ATTRIBUTES {
+                    let Some(i) = attributes.iter().position(|(name, _)| name == theme) else {
+                        continue;
+                    };
+                    let Some(typeface) = self.typeface(&attributes[i].1) else {
+                        continue;
+                    };
+                    let typeface = typeface.to_string();
+                    attributes.remove(i);
+                    match attributes.iter_mut().find(|(name, _)| name == font) {
+                        Some(existing) => existing.1 = typeface,
+                        None => attributes.push((font.to_string(), typeface)),
+                    }
+                }
+                let attributes: String = attributes
+                    .iter()
+                    .map(|(name, value)| format!(r#" {}="{}""#, name, value))
+                    .collect();
+                format!("<w:rFonts{}/>", attributes)
+            })
+            .into_owned()
+    }
+}
+
+// the id of the default style of each style type
+fn default_styles(styles: &str) -> HashMap<String, String> {
+    STYLE
+        .find_iter(styles)
+        .map(|m| m.as_str())
+        .filter(|style| STYLE_DEFAULT.is_match(style))
+        .filter_map(|style| {
+            let kind = STYLE_TYPE.captures(style)?[1].to_string();
+            Some((kind, STYLE_ID.captures(style)?[1].to_string()))
+        })
+        .collect()
+}
+
+// comments are a document-level part that is not copied, nor are notes of
+// a kind other than those `copied`, so references to them would point at
+// the target's own
+fn remove_note_references(xml: &str, copied: &[&str]) -> (String, Vec<String>) {
+    let mut removed: Vec<(String, usize)> = Vec::new();
+    let xml = NOTE_REF.replace_all(xml, |c: &Captures| {
+        let Some(kind) = c.get(1).map(|k| k.as_str()) else {
+            return String::new();
+        };
+        if copied.contains(&kind) {
+            return c[0].to_string();
+        }
+        match removed.iter_mut().find(|(k, _)| *k == kind) {
+            Some((_, count)) => *count += 1,
+            None => removed.push((kind.to_string(), 1)),
+        }
+        String::new()
+    });
+    let warnings = removed
+        .into_iter()
+        .map(|(kind, count)| match kind.as_str() {
+            "comment" => format!(
+                "{} comment(s) left out, as the comments of an inserted document are not copied",
+                count
+            ),
+            _ => format!(
+                "{} {}(s) left out, as the document has no {}s part",
+                count, kind, kind
+            ),
+        })
+        .collect();
+    (xml.into_owned(), warnings)
+}
+
+// replace the second group of each match whose value has a replacement
+fn replace_values(regex: &Regex, xml: &str, values: &HashMap<String, String>) -> String {
+    if values.is_empty() {
+        return xml.to_string();
+    }
+    regex
+        .replace_all(xml, |c: &Captures| {
+            let value = values.get(&c[2]).map(String::as_str).unwrap_or(&c[2]);
+            format!("{}{}{}", &c[1], value, &c[3])
+        })
+        .into_owned()
+}
+
+// the largest number held by the second group of the regex
+fn max_value(regex: &Regex, xml: &str) -> u64 {
+    regex
+        .captures_iter(xml)
+        .filter_map(|c| c[2].parse().ok())
+        .max()
+        .unwrap_or(0)
+}
+
+// a paragraph ending a section with the given properties
+fn section_break(sect_pr: &str) -> String {
+    format!("<w:p><w:pPr>{}</w:pPr></w:p>", sect_pr)
+}
+
+fn body_start(xml: &str) -> Result<usize> {
+    let start = xml.find("<w:body").context("The document has no body")?;
+    Ok(start + xml[start..].find('>').context("The document has no body")? + 1)
+}
+
+fn root_element(xml: &str) -> Result<&str> {
+    // past the XML declaration and any comments
+    let start = xml
+        .match_indices('<')
+        .map(|(i, _)| i)
+        .find(|&i| xml[i + 1..].starts_with(|c: char| c.is_alphabetic()))
+        .context("Invalid XML part")?;
+    let end = start + xml[start..].find('>').context("Invalid XML part")? + 1;
+    Ok(&xml[start..end])
+}
+
+/// A name for a copy of the part that is not used in the target.
+pub fn unused_name(target: &Package, name: &str) -> String {
+    if target.part(name).is_none() {
+        return name.to_string();
+    }
+    let (stem, ext) = match name.rfind('.') {
+        Some(dot) if dot > name.rfind('/').unwrap_or(0) => name.split_at(dot),
+        _ => (name, ""),
+    };
+    (2..)
+        .map(|i| format!("{}-{}{}", stem, i, ext))
+        .find(|n| target.part(n).is_none())
+        .unwrap()
+}
+
+fn parent(name: &str) -> &str {
+    name.rfind('/').map(|i| &name[..i]).unwrap_or("")
+}
+
+fn extension(name: &str) -> String {
+    name.rsplit('.').next().unwrap_or_default().to_lowercase()
+}
+
+fn rels_name(name: &str) -> String {
+    let file = name.rsplit('/').next().unwrap_or(name);
+    match parent(name) {
+        "" => format!("_rels/{}.rels", file),
+        dir => format!("{}/_rels/{}.rels", dir, file),
+    }
+}
+
+/// The part name a relationship target refers to from a part in `dir`.
+pub fn resolve(dir: &str, target: &str) -> String {
+    if let Some(absolute) = target.strip_prefix('/') {
+        return absolute.to_string();
+    }
+    let mut segments: Vec<&str> = dir.split('/').filter(|s| !s.is_empty()).collect();
+    for segment in target.split('/') {
+        match segment {
+            "." | "" => {}
+            ".." => {
+                segments.pop();
+            }
+            s => segments.push(s),
+        }
+    }
+    segments.join("/")
+}
+
+/// The relationship target of a part from a part in `dir`.
+pub fn relative(dir: &str, name: &str) -> String {
+    match name.strip_prefix(&format!("{}/", dir)) {
+        Some(rest) if !dir.is_empty() => rest.to_string(),
+        _ => format!("/{}", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAMESPACES: &str = r#"xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main" xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships""#;
+    const TYPES: &str = r#"<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types"><Default Extension="xml" ContentType="application/xml"/><Default Extension="png" ContentType="image/png"/></Types>"#;
+    const TARGET_SECTION: &str = r#"<w:sectPr><w:pgSz w:w="100"/></w:sectPr>"#;
+    const SOURCE_SECTION: &str = r#"<w:sectPr><w:pgSz w:w="200"/></w:sectPr>"#;
+
+    fn document(body: &str) -> String {
+        format!(
+            r#"<w:document {}><w:body>{}</w:body></w:document>"#,
+            NAMESPACES, body
+        )
+    }
+
+    fn rels(relationships: &str) -> String {
+        format!(
+            r#"<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">{}</Relationships>"#,
+            relationships
+        )
+    }
+
+    fn styles(styles: &str) -> String {
+        format!("<w:styles {}>{}</w:styles>", NAMESPACES, styles)
+    }
+
+    fn package(body: &str) -> Package {
+        Package::from_parts(&[
+            (CONTENT_TYPES_XML, TYPES),
+            (DOCUMENT_XML, &document(body)),
+            (DOCUMENT_RELS, &rels("")),
+        ])
+    }
+
+    fn paragraph(text: &str) -> String {
+        format!("<w:p><w:r><w:t>{}</w:t></w:r></w:p>", text)
+    }
+
+    fn body(package: &Package) -> String {
+        let xml = package.xml(DOCUMENT_XML).unwrap();
+        let start = body_start(&xml).unwrap();
+        let end = xml.rfind("</w:body>").unwrap();
+        xml[start..end].to_string()
+    }
+
+    // insert `source` in place of a paragraph reading MARKER
+    fn merge(target: &mut Package, source: &Package) -> Vec<String> {
+        insert(target, source, "MARKER").unwrap()
+    }
+
+    #[test]
+    fn relationships_get_unused_ids() {
+        let mut target = package(&format!("{}{}", paragraph("MARKER"), TARGET_SECTION));
+        let image = format!("{}/image", RELATIONSHIP_TYPES);
+        let target_rels = format!(
+            r#"<Relationship Id="rId1" Type="{}" Target="media/image1.png"/>"#,
+            image
+        );
+        target.set_part(DOCUMENT_RELS, rels(&target_rels).into_bytes());
+        target.set_part("word/media/image1.png", b"target".to_vec());
+
+        let mut source = package(
+            r#"<w:p><w:r><w:drawing><a:blip r:embed="rId1"/></w:drawing></w:r><w:hyperlink r:id="rId2"><w:r><w:t>link</w:t></w:r></w:hyperlink></w:p>"#,
+        );
+        let source_rels = format!(
+            r#"<Relationship Id="rId1" Type="{}" Target="media/image1.png"/><Relationship Id="rId2" Type="{}/hyperlink" Target="https://example.com" TargetMode="External"/><Relationship Id="rId3" Type="{}/styles" Target="styles.xml"/>"#,
+            image, RELATIONSHIP_TYPES, RELATIONSHIP_TYPES
+        );
+        source.set_part(DOCUMENT_RELS, rels(&source_rels).into_bytes());
+        source.set_part("word/media/image1.png", b"source".to_vec());
+
+        merge(&mut target, &source);
+        assert_eq!(
+            body(&target),
+            format!(
+                r#"<w:p><w:r><w:drawing><a:blip r:embed="rId2"/></w:drawing></w:r><w:hyperlink r:id="rId3"><w:r><w:t>link</w:t></w:r></w:hyperlink></w:p>{}"#,
+                TARGET_SECTION
+            )
+        );
+        let rels = relationships(&target.xml(DOCUMENT_RELS).unwrap());
+        let found: Vec<_> = rels
+            .iter()
+            .map(|r| (r.id.as_str(), r.target.as_str(), r.external))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("rId1", "media/image1.png", false),
+                ("rId2", "media/image1-2.png", false),
+                ("rId3", "https://example.com", true),
+            ]
+        );
+        assert_eq!(target.part("word/media/image1.png"), Some(&b"target"[..]));
+        assert_eq!(target.part("word/media/image1-2.png"), Some(&b"source"[..]));
+    }
+
+    #[test]
+    fn unused_names() {
+        let mut target = package("");
+        target.set_part("word/media/image1.png", Vec::new());
+        target.set_part("word/media/image1-2.png", Vec::new());
+        target.set_part("word.d/part", Vec::new());
+
+        assert_eq!(
+            unused_name(&target, "word/media/image2.png"),
+            "word/media/image2.png"
+        );
+        assert_eq!(
+            unused_name(&target, "word/media/image1.png"),
+            "word/media/image1-3.png"
+        );
+        // a dot in a directory is not an extension
+        assert_eq!(unused_name(&target, "word.d/part"), "word.d/part-2");
+    }
+
+    #[test]
+    fn lists_are_renumbered() {
+        let mut target = package(&format!("{}{}", paragraph("MARKER"), TARGET_SECTION));
+        target.set_part(
+            NUMBERING_XML,
+            format!(
+                r#"<w:numbering {}><w:abstractNum w:abstractNumId="0"><w:lvl w:ilvl="0"/></w:abstractNum><w:num w:numId="1"><w:abstractNumId w:val="0"/></w:num></w:numbering>"#,
+                NAMESPACES
+            )
+            .into_bytes(),
+        );
+        let mut source = package(
+            r#"<w:p><w:pPr><w:numPr><w:ilvl w:val="0"/><w:numId w:val="2"/></w:numPr></w:pPr></w:p>"#,
+        );
+        source.set_part(
+            NUMBERING_XML,
+            format!(
+                r#"<w:numbering {}><w:abstractNum w:abstractNumId="0"><w:lvl w:ilvl="0"><w:start w:val="5"/></w:lvl></w:abstractNum><w:num w:numId="1"><w:abstractNumId w:val="0"/></w:num><w:num w:numId="2"><w:abstractNumId w:val="0"/></w:num></w:numbering>"#,
+                NAMESPACES
+            )
+            .into_bytes(),
+        );
+
+        merge(&mut target, &source);
+        assert_eq!(
+            target.xml(NUMBERING_XML).unwrap(),
+            format!(
+                r#"<w:numbering {}><w:abstractNum w:abstractNumId="0"><w:lvl w:ilvl="0"/></w:abstractNum><w:abstractNum w:abstractNumId="1"><w:lvl w:ilvl="0"><w:start w:val="5"/></w:lvl></w:abstractNum><w:num w:numId="1"><w:abstractNumId w:val="0"/></w:num><w:num w:numId="2"><w:abstractNumId w:val="1"/></w:num><w:num w:numId="3"><w:abstractNumId w:val="1"/></w:num></w:numbering>"#,
+                NAMESPACES
+            )
+        );
+        assert!(body(&target).contains(r#"<w:numId w:val="3"/>"#));
+    }
+
+    const NORMAL: &str = r#"<w:style w:type="paragraph" w:default="1" w:styleId="Normal"><w:name w:val="Normal"/></w:style>"#;
+    const DOC_DEFAULTS: &str = r#"<w:docDefaults><w:rPrDefault><w:rPr><w:sz w:val="24"/></w:rPr></w:rPrDefault></w:docDefaults>"#;
+
+    #[test]
+    fn conflicting_styles_are_renamed() {
+        let mut target = package(&format!("{}{}", paragraph("MARKER"), TARGET_SECTION));
+        let same =
+            r#"<w:style w:type="paragraph" w:styleId="Same"><w:name w:val="Same"/></w:style>"#;
+        target.set_part(
+            STYLES_XML,
+            styles(&format!(
+                r#"{}{}<w:style w:type="paragraph" w:styleId="Heading1"><w:name w:val="heading 1"/><w:basedOn w:val="Normal"/><w:rPr><w:b/></w:rPr></w:style>{}"#,
+                DOC_DEFAULTS, NORMAL, same
+            ))
+            .into_bytes(),
+        );
+        let mut source = package(
+            r#"<w:p><w:pPr><w:pStyle w:val="Heading1"/></w:pPr></w:p><w:p><w:pPr><w:pStyle w:val="Same"/></w:pPr></w:p>"#,
+        );
+        source.set_part(
+            STYLES_XML,
+            styles(&format!(
+                r#"{}{}<w:style w:type="paragraph" w:styleId="Heading1"><w:name w:val="heading 1"/><w:basedOn w:val="Normal"/><w:rPr><w:i/></w:rPr></w:style>{}<w:style w:type="paragraph" w:styleId="Quote"><w:name w:val="Quote"/><w:basedOn w:val="Heading1"/></w:style>"#,
+                DOC_DEFAULTS, NORMAL, same
+            ))
+            .into_bytes(),
+        );
+
+        merge(&mut target, &source);
+        let merged = target.xml(STYLES_XML).unwrap();
+        assert!(merged.ends_with(r#"<w:style w:type="paragraph" w:styleId="Same"><w:name w:val="Same"/></w:style><w:style w:type="paragraph" w:styleId="Heading1-2"><w:name w:val="heading 1 (2)"/><w:basedOn w:val="Normal"/><w:rPr><w:i/></w:rPr></w:style><w:style w:type="paragraph" w:styleId="Quote"><w:name w:val="Quote"/><w:basedOn w:val="Heading1-2"/></w:style></w:styles>"#));
+        // an identical default style leaves unstyled content alone
+        assert_eq!(
+            body(&target),
+            format!(
+                r#"<w:p><w:pPr><w:pStyle w:val="Heading1-2"/></w:pPr></w:p><w:p><w:pPr><w:pStyle w:val="Same"/></w:pPr></w:p>{}"#,
+                TARGET_SECTION
+            )
+        );
+    }
+
+    #[test]
+    fn renamed_default_style_is_given_to_unstyled_content() {
+        let mut target = package(&format!("{}{}", paragraph("MARKER"), TARGET_SECTION));
+        target.set_part(
+            STYLES_XML,
+            styles(&format!("{}{}", DOC_DEFAULTS, NORMAL)).into_bytes(),
+        );
+        let mut source = package(concat!(
+            "<w:p><w:r><w:t>a</w:t></w:r></w:p>",
+            "<w:p><w:pPr/><w:r/></w:p>",
+            r#"<w:p><w:pPr><w:jc w:val="left"/></w:pPr></w:p>"#,
+            "<w:p/>",
+            r#"<w:p><w:pPr><w:pStyle w:val="Other"/></w:pPr></w:p>"#,
+        ));
+        source.set_part(
+            STYLES_XML,
+            styles(&format!(
+                r#"{}<w:style w:type="paragraph" w:default="1" w:styleId="Normal"><w:name w:val="Normal"/><w:pPr><w:jc w:val="center"/></w:pPr></w:style>"#,
+                DOC_DEFAULTS
+            ))
+            .into_bytes(),
+        );
+
+        merge(&mut target, &source);
+        assert!(target.xml(STYLES_XML).unwrap().ends_with(r#"<w:style w:type="paragraph" w:styleId="Normal-2"><w:name w:val="Normal (2)"/><w:pPr><w:jc w:val="center"/></w:pPr></w:style></w:styles>"#));
+        let styled = r#"<w:pPr><w:pStyle w:val="Normal-2"/></w:pPr>"#;
+        assert_eq!(
+            body(&target),
+            format!(
+                r#"<w:p>{}<w:r><w:t>a</w:t></w:r></w:p><w:p>{}<w:r/></w:p><w:p><w:pPr><w:pStyle w:val="Normal-2"/><w:jc w:val="left"/></w:pPr></w:p><w:p>{}</w:p><w:p><w:pPr><w:pStyle w:val="Other"/></w:pPr></w:p>{}"#,
+                styled, styled, styled, TARGET_SECTION
+            )
+        );
+    }
+
+    #[test]
+    fn document_defaults_are_folded_into_the_default_style() {
+        let mut target = package(&format!("{}{}", paragraph("MARKER"), TARGET_SECTION));
+        target.set_part(
+            STYLES_XML,
+            styles(&format!("{}{}", DOC_DEFAULTS, NORMAL)).into_bytes(),
+        );
+        let mut source = package(&paragraph("a"));
+        source.set_part(
+            STYLES_XML,
+            styles(&format!(
+                r#"<w:docDefaults><w:rPrDefault><w:rPr><w:sz w:val="30"/></w:rPr></w:rPrDefault></w:docDefaults>{}"#,
+                NORMAL
+            ))
+            .into_bytes(),
+        );
+
+        merge(&mut target, &source);
+        assert!(target.xml(STYLES_XML).unwrap().ends_with(r#"<w:style w:type="paragraph" w:styleId="DocDefaults"><w:name w:val="Document Defaults"/><w:semiHidden/><w:rPr><w:sz w:val="30"/></w:rPr></w:style><w:style w:type="paragraph" w:styleId="Normal-2"><w:name w:val="Normal (2)"/><w:basedOn w:val="DocDefaults"/></w:style></w:styles>"#));
+        assert_eq!(
+            body(&target),
+            format!(
+                r#"<w:p><w:pPr><w:pStyle w:val="Normal-2"/></w:pPr><w:r><w:t>a</w:t></w:r></w:p>{}"#,
+                TARGET_SECTION
+            )
+        );
+    }
+
+    #[test]
+    fn inserts_take_a_section_of_their_own() {
+        let section_break = |sect_pr: &str| format!("<w:p><w:pPr>{}</w:pPr></w:p>", sect_pr);
+        let (a, b, s) = (paragraph("A"), paragraph("B"), paragraph("S"));
+        let marker = paragraph("MARKER");
+        let with_section = format!("{}{}", s, SOURCE_SECTION);
+        let cases = [
+            // at the start of the document
+            (
+                format!("{}{}{}", marker, a, TARGET_SECTION),
+                &with_section,
+                format!(
+                    "{}{}{}{}",
+                    s,
+                    section_break(SOURCE_SECTION),
+                    a,
+                    TARGET_SECTION
+                ),
+            ),
+            (
+                format!("{}{}{}", marker, a, TARGET_SECTION),
+                &s,
+                format!(
+                    "{}{}{}{}",
+                    s,
+                    section_break(TARGET_SECTION),
+                    a,
+                    TARGET_SECTION
+                ),
+            ),
+            // at the end, where the insert's section becomes the last
+            (
+                format!("{}{}{}", a, marker, TARGET_SECTION),
+                &with_section,
+                format!(
+                    "{}{}{}{}",
+                    a,
+                    section_break(TARGET_SECTION),
+                    s,
+                    SOURCE_SECTION
+                ),
+            ),
+            (
+                format!("{}{}{}", a, marker, TARGET_SECTION),
+                &s,
+                format!(
+                    "{}{}{}{}",
+                    a,
+                    section_break(TARGET_SECTION),
+                    s,
+                    TARGET_SECTION
+                ),
+            ),
+            // between paragraphs
+            (
+                format!("{}{}{}{}", a, marker, b, TARGET_SECTION),
+                &with_section,
+                format!(
+                    "{}{}{}{}{}{}",
+                    a,
+                    section_break(TARGET_SECTION),
+                    s,
+                    section_break(SOURCE_SECTION),
+                    b,
+                    TARGET_SECTION
+                ),
+            ),
+            (
+                format!("{}{}{}{}", a, marker, b, TARGET_SECTION),
+                &s,
+                format!(
+                    "{}{}{}{}{}{}",
+                    a,
+                    section_break(TARGET_SECTION),
+                    s,
+                    section_break(TARGET_SECTION),
+                    b,
+                    TARGET_SECTION
+                ),
+            ),
+        ];
+        for (target_body, source_body, expected) in cases {
+            let mut target = package(&target_body);
+            merge(&mut target, &package(source_body));
+            assert_eq!(body(&target), expected, "inserting into {}", target_body);
+        }
+    }
+
+    #[test]
+    fn headers_and_footers_are_blanked() {
+        let mut target = package("");
+        let previous = r#"<w:sectPr><w:headerReference w:type="default" r:id="rId7"/><w:footerReference w:type="first" r:id="rId8"/></w:sectPr>"#;
+
+        let sect_pr = blank_references(
+            &mut target,
+            r#"<w:sectPr><w:headerReference w:type="default" r:id="rId9"/><w:pgSz/></w:sectPr>"#,
+            previous,
+        )
+        .unwrap();
+        assert_eq!(
+            sect_pr,
+            r#"<w:sectPr><w:footerReference w:type="first" r:id="rId1"/><w:headerReference w:type="default" r:id="rId9"/><w:pgSz/></w:sectPr>"#
+        );
+        assert!(target.part("word/footer-blank.xml").is_some());
+        assert!(target.part("word/header-blank.xml").is_none());
+        let rels = relationships(&target.xml(DOCUMENT_RELS).unwrap());
+        assert_eq!(rels[0].target, "footer-blank.xml");
+        assert_eq!(
+            content_type(
+                &target.xml(CONTENT_TYPES_XML).unwrap(),
+                "word/footer-blank.xml"
+            )
+            .as_deref(),
+            Some("application/vnd.openxmlformats-officedocument.wordprocessingml.footer+xml")
+        );
+
+        let sect_pr = blank_references(&mut target, "<w:sectPr/>", previous).unwrap();
+        assert_eq!(
+            sect_pr,
+            r#"<w:sectPr><w:headerReference w:type="default" r:id="rId2"/><w:footerReference w:type="first" r:id="rId3"/></w:sectPr>"#
+        );
+        assert!(target.part("word/header-blank.xml").is_some());
+        assert!(target.part("word/footer-blank-2.xml").is_some());
+    }
+
+    #[test]
+    fn note_references_are_removed() {
+        let mut target = package(&format!("{}{}", paragraph("MARKER"), TARGET_SECTION));
+        let source = package(
+            r#"<w:p><w:commentRangeStart w:id="0"/><w:r><w:t>a</w:t></w:r><w:r><w:footnoteReference w:id="1"/></w:r><w:commentRangeEnd w:id="0"/><w:r><w:commentReference w:id="0"/></w:r></w:p>"#,
+        );
+
+        let warnings = merge(&mut target, &source);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with("1 footnote(s) left out"));
+        assert!(warnings[1].starts_with("1 comment(s) left out"));
+        assert_eq!(
+            body(&target),
+            format!(
+                "<w:p><w:r><w:t>a</w:t></w:r><w:r></w:r><w:r></w:r></w:p>{}",
+                TARGET_SECTION
+            )
+        );
+    }
+
+    #[test]
+    fn bookmarks_are_renumbered_and_renamed() {
+        let mut target = package(&format!(
+            r#"<w:p><w:bookmarkStart w:id="0" w:name="_GoBack"/><w:bookmarkEnd w:id="0"/><w:bookmarkStart w:id="4" w:name="intro"/><w:bookmarkEnd w:id="4"/></w:p>{}{}"#,
+            paragraph("MARKER"),
+            TARGET_SECTION
+        ));
+        let source = package(concat!(
+            r#"<w:p><w:bookmarkStart w:id="0" w:name="intro"/><w:r><w:t>a</w:t></w:r><w:bookmarkEnd w:id="0"/>"#,
+            r#"<w:bookmarkStart w:id="1" w:name="other"/><w:bookmarkEnd w:id="1"/></w:p>"#,
+            r#"<w:p><w:hyperlink w:anchor="intro"/><w:r><w:instrText xml:space="preserve"> PAGEREF intro \h </w:instrText></w:r>"#,
+            r#"<w:hyperlink w:anchor="other"/></w:p>"#,
+        ));
+
+        merge(&mut target, &source);
+        let xml = body(&target);
+        let inserted = &xml[xml.find("<w:p>").unwrap()..];
+        assert!(inserted.contains(concat!(
+            r#"<w:p><w:bookmarkStart w:id="5" w:name="intro_2"/><w:r><w:t>a</w:t></w:r><w:bookmarkEnd w:id="5"/>"#,
+            r#"<w:bookmarkStart w:id="6" w:name="other"/><w:bookmarkEnd w:id="6"/></w:p>"#,
+            r#"<w:p><w:hyperlink w:anchor="intro_2"/><w:r><w:instrText xml:space="preserve"> PAGEREF intro_2 \h </w:instrText></w:r>"#,
+            r#"<w:hyperlink w:anchor="other"/></w:p>"#,
+        )));
+        // the target's own bookmarks are left alone
+        assert!(xml.starts_with(r#"<w:p><w:bookmarkStart w:id="0" w:name="_GoBack"/><w:bookmarkEnd w:id="0"/><w:bookmarkStart w:id="4" w:name="intro"/>"#));
+    }
+
+    fn with_theme(package: &mut Package, latin: &str) {
+        package.set_part(
+            DOCUMENT_RELS,
+            rels(&format!(
+                r#"<Relationship Id="rId1" Type="{}/theme" Target="theme/theme1.xml"/>"#,
+                RELATIONSHIP_TYPES
+            ))
+            .into_bytes(),
+        );
+        package.set_part(
+            "word/theme/theme1.xml",
+            format!(
+                r#"<a:theme xmlns:a="http://schemas.openxmlformats.org/drawingml/2006/main"><a:themeElements><a:fontScheme><a:majorFont><a:latin typeface="{} Light"/><a:ea typeface=""/><a:cs typeface=""/></a:majorFont><a:minorFont><a:latin typeface="{}"/><a:ea typeface=""/><a:cs typeface="Arial"/></a:minorFont></a:fontScheme></a:themeElements></a:theme>"#,
+                latin, latin
+            )
+            .into_bytes(),
+        );
+    }
+
+    #[test]
+    fn theme_fonts_are_resolved() {
+        let mut target = package(&format!("{}{}", paragraph("MARKER"), TARGET_SECTION));
+        with_theme(&mut target, "Calibri");
+        let heading = r#"<w:style w:type="paragraph" w:styleId="Heading1"><w:name w:val="heading 1"/><w:rPr><w:rFonts w:asciiTheme="majorHAnsi" w:hAnsiTheme="majorHAnsi"/></w:rPr></w:style>"#;
+        target.set_part(
+            STYLES_XML,
+            styles(&format!("{}{}{}", DOC_DEFAULTS, NORMAL, heading)).into_bytes(),
+        );
+        let mut source = package(concat!(
+            r#"<w:p><w:pPr><w:pStyle w:val="Heading1"/></w:pPr></w:p>"#,
+            r#"<w:p><w:r><w:rPr><w:rFonts w:ascii="Courier" w:asciiTheme="minorAscii" w:eastAsiaTheme="minorEastAsia" w:cstheme="minorBidi"/></w:rPr></w:r></w:p>"#,
+        ));
+        with_theme(&mut source, "Cambria");
+        source.set_part(
+            STYLES_XML,
+            styles(&format!("{}{}{}", DOC_DEFAULTS, NORMAL, heading)).into_bytes(),
+        );
+
+        merge(&mut target, &source);
+        // the same definition looks different in another theme
+        assert!(target.xml(STYLES_XML).unwrap().ends_with(r#"<w:style w:type="paragraph" w:styleId="Heading1-2"><w:name w:val="heading 1 (2)"/><w:rPr><w:rFonts w:ascii="Cambria Light" w:hAnsi="Cambria Light"/></w:rPr></w:style></w:styles>"#));
+        // a typeface the theme leaves empty stays a theme reference
+        assert_eq!(
+            body(&target),
+            format!(
+                r#"<w:p><w:pPr><w:pStyle w:val="Heading1-2"/></w:pPr></w:p><w:p><w:r><w:rPr><w:rFonts w:ascii="Cambria" w:eastAsiaTheme="minorEastAsia" w:cs="Arial"/></w:rPr></w:r></w:p>{}"#,
+                TARGET_SECTION
+            )
+        );
+
+        // with the same theme, references are kept
+        let mut target = package(&format!("{}{}", paragraph("MARKER"), TARGET_SECTION));
+        with_theme(&mut target, "Cambria");
+        merge(&mut target, &source);
+        assert!(body(&target).contains(r#"<w:rFonts w:ascii="Courier" w:asciiTheme="minorAscii""#));
+    }
+
+    #[test]
+    fn even_page_headers_are_carried_over() {
+        let settings =
+            |settings: &str| format!("<w:settings {}>{}</w:settings>", NAMESPACES, settings);
+        let mut target = package(&format!(
+            r#"<w:p><w:pPr><w:sectPr><w:headerReference w:type="default" r:id="rId1"/><w:footerReference w:type="default" r:id="rId2"/><w:footerReference w:type="even" r:id="rId3"/></w:sectPr></w:pPr></w:p>{}<w:sectPr/>"#,
+            paragraph("MARKER")
+        ));
+        target.set_part(
+            SETTINGS_XML,
+            settings(r#"<w:zoom w:percent="100"/><w:defaultTabStop w:val="720"/><w:compat/>"#)
+                .into_bytes(),
+        );
+        let mut source = package(&paragraph("a"));
+        source.set_part(
+            SETTINGS_XML,
+            settings("<w:evenAndOddHeaders/>").into_bytes(),
+        );
+
+        merge(&mut target, &source);
+        assert_eq!(
+            target.xml(SETTINGS_XML).unwrap(),
+            settings(
+                r#"<w:zoom w:percent="100"/><w:defaultTabStop w:val="720"/><w:evenAndOddHeaders/><w:compat/>"#
+            )
+        );
+        assert!(body(&target).starts_with(r#"<w:p><w:pPr><w:sectPr><w:headerReference w:type="default" r:id="rId1"/><w:headerReference w:type="even" r:id="rId1"/><w:footerReference w:type="default" r:id="rId2"/><w:footerReference w:type="even" r:id="rId3"/></w:sectPr></w:pPr></w:p>"#));
+
+        // a source that turns them off leaves the target alone
+        let mut target = package(&format!("{}{}", paragraph("MARKER"), TARGET_SECTION));
+        target.set_part(SETTINGS_XML, settings("").into_bytes());
+        source.set_part(
+            SETTINGS_XML,
+            settings(r#"<w:evenAndOddHeaders w:val="false"/>"#).into_bytes(),
+        );
+        merge(&mut target, &source);
+        assert_eq!(target.xml(SETTINGS_XML).unwrap(), settings(""));
+    }
+
+    #[test]
+    fn notes_are_copied_under_new_ids() {
+        let notes = |kind: &str, notes: &str| {
+            format!(
+                r#"<w:{}s {}><w:{} w:type="separator" w:id="-1"><w:p/></w:{}><w:{} w:type="continuationSeparator" w:id="0"><w:p/></w:{}>{}</w:{}s>"#,
+                kind, NAMESPACES, kind, kind, kind, kind, notes, kind
+            )
+        };
+        let notes_rels = |relationships: &str| {
+            rels(&format!(
+                r#"<Relationship Id="rId1" Type="{}/footnotes" Target="footnotes.xml"/>{}"#,
+                RELATIONSHIP_TYPES, relationships
+            ))
+        };
+        let mut target = package(&format!(
+            r#"<w:p><w:r><w:footnoteReference w:id="1"/></w:r></w:p>{}{}"#,
+            paragraph("MARKER"),
+            TARGET_SECTION
+        ));
+        target.set_part(DOCUMENT_RELS, notes_rels("").into_bytes());
+        let target_note =
+            r#"<w:footnote w:id="1"><w:p><w:r><w:t>target</w:t></w:r></w:p></w:footnote>"#;
+        target.set_part(
+            "word/footnotes.xml",
+            notes("footnote", target_note).into_bytes(),
+        );
+
+        let mut source = package(concat!(
+            r#"<w:p><w:r><w:footnoteReference w:id="2"/></w:r><w:r><w:footnoteReference w:id="1"/></w:r>"#,
+            r#"<w:r><w:endnoteReference w:id="1"/></w:r></w:p>"#,
+        ));
+        source.set_part(
+            DOCUMENT_RELS,
+            notes_rels(&format!(
+                r#"<Relationship Id="rId2" Type="{}/endnotes" Target="endnotes.xml"/>"#,
+                RELATIONSHIP_TYPES
+            ))
+            .into_bytes(),
+        );
+        source.set_part(
+            "word/footnotes.xml",
+            notes(
+                "footnote",
+                concat!(
+                    r#"<w:footnote w:id="1"><w:p><w:hyperlink r:id="rId1"/></w:p></w:footnote>"#,
+                    r#"<w:footnote w:id="2"><w:p><w:r><w:t>two</w:t></w:r></w:p></w:footnote>"#,
+                ),
+            )
+            .into_bytes(),
+        );
+        source.set_part(
+            "word/_rels/footnotes.xml.rels",
+            rels(&format!(
+                r#"<Relationship Id="rId1" Type="{}/hyperlink" Target="https://example.com" TargetMode="External"/>"#,
+                RELATIONSHIP_TYPES
+            ))
+            .into_bytes(),
+        );
+        let endnotes = notes(
+            "endnote",
+            r#"<w:endnote w:id="1"><w:p><w:r><w:t>end</w:t></w:r></w:p></w:endnote>"#,
+        );
+        source.set_part("word/endnotes.xml", endnotes.clone().into_bytes());
+
+        let warnings = merge(&mut target, &source);
+        assert!(warnings.is_empty());
+        assert!(body(&target).contains(concat!(
+            r#"<w:p><w:r><w:footnoteReference w:id="3"/></w:r><w:r><w:footnoteReference w:id="2"/></w:r>"#,
+            r#"<w:r><w:endnoteReference w:id="1"/></w:r></w:p>"#,
+        )));
+        assert_eq!(
+            target.xml("word/footnotes.xml").unwrap(),
+            notes(
+                "footnote",
+                &format!(
+                    r#"{}<w:footnote w:id="2"><w:p><w:hyperlink r:id="rId1"/></w:p></w:footnote><w:footnote w:id="3"><w:p><w:r><w:t>two</w:t></w:r></w:p></w:footnote>"#,
+                    target_note
+                )
+            )
+        );
+        let rels = relationships(&target.xml("word/_rels/footnotes.xml.rels").unwrap());
+        assert_eq!(rels[0].target, "https://example.com");
+        // a target without endnotes takes the source's as they are
+        assert_eq!(target.xml("word/endnotes.xml").unwrap(), endnotes);
+        assert!(relationships(&target.xml(DOCUMENT_RELS).unwrap())
+            .iter()
+            .any(|r| r.target == "endnotes.xml"));
+    }
+}
@@ -0,0 +1,281 @@
+//! Rasterising of SVG images to PNG ahead of pandoc, as Word before 2016
+//! shows SVG images as blank boxes.
+//!
+//! Conversions are kept in the image cache, named by a hash of the SVG and
+//! the DPI, so an unchanged image is only converted once. When the SVG is
+//! wanted as well, it is added to the package once pandoc has run and linked
+//! from the picture holding the PNG, which newer versions of Word show in its
+//! place.
+
+use crate::{
+    cache::{self, Fingerprint},
+    docx::{Package, DOCUMENT_XML},
+    merge::{
+        add_content_type, add_relationship, relationships, relative, resolve, unused_name,
+        CONTENT_TYPES_XML, DOCUMENT_RELS, RELATIONSHIP_TYPES,
+    },
+    validate::local_path,
+};
+use anyhow::{Context, Result};
+use regex::{Captures, Regex};
+use resvg::{tiny_skia, usvg};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+
+// the a:blip extension Word reads an SVG alternative from
+const SVG_EXTENSION_URI: &str = "{96DAC541-7B7A-43D3-8B79-37D633B846F1}";
+const SVG_NAMESPACE: &str = "http://schemas.microsoft.com/office/drawing/2016/SVG/main";
+
+// the DPI Word lays images out at
+const WORD_DPI: f32 = 96.0;
+
+static EMPTY_BLIP: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"<a:blip\b([^>]*?)\br:embed="([^"]*)"([^>]*?)\s*/>"#).unwrap());
+
+/// An SVG image and the PNG it was converted to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Converted {
+    pub svg: PathBuf,
+    pub png: PathBuf,
+}
+
+/// Converts the SVG images of a document, remembering each one converted.
+pub struct Converter<'a> {
+    dir: PathBuf,
+    resource_path: &'a [PathBuf],
+    dpi: u32,
+    // loading the system fonts is slow, so it is left until an image needs it
+    options: Option<usvg::Options<'static>>,
+    converted: Vec<Converted>,
+}
+
+impl<'a> Converter<'a> {
+    /// A converter keeping its PNGs in `dir`, which looks images up on the
+    /// `resource_path` the way pandoc does.
+    pub fn new(dir: PathBuf, resource_path: &'a [PathBuf], dpi: u32) -> Self {
+        Self {
+            dir,
+            resource_path,
+            dpi,
+            options: None,
+            converted: Vec::new(),
+        }
+    }
+
+    /// The source of the PNG to use in place of an image, or `None` for an
+    /// image that is not a local SVG.
+    pub fn convert(&mut self, dest: &str) -> Result<Option<String>> {
+        // generated diagrams are referred to by their absolute path
+        let target = match Path::new(dest).is_absolute() {
+            true => PathBuf::from(dest),
+            false => match local_path(dest) {
+                Some(target) => target,
+                None => return Ok(None),
+            },
+        };
+        let is_svg = target
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("svg"));
+        // a missing image is reported by validation, pandoc is left to skip it
+        let found = self
+            .resource_path
+            .iter()
+            .map(|dir| dir.join(&target))
+            .find(|path| path.is_file());
+        let (true, Some(svg)) = (is_svg, found) else {
+            return Ok(None);
+        };
+
+        let data = fs::read(&svg).with_context(|| format!("Unable to read {}", svg.display()))?;
+        let mut fingerprint = Fingerprint::new();
+        fingerprint
+            .add("dpi", &self.dpi.to_le_bytes())
+            .add("svg", &data);
+        let png = self.dir.join(format!("{}.png", fingerprint.finish()));
+        if !png.exists() {
+            let image = self
+                .rasterise(&data, svg.parent())
+                .with_context(|| format!("Unable to convert {}", svg.display()))?;
+            cache::store(&png, &image)
+                .with_context(|| format!("Unable to write {}", png.display()))?;
+        }
+
+        let converted = Converted {
+            svg,
+            png: png.clone(),
+        };
+        if !self.converted.contains(&converted) {
+            self.converted.push(converted);
+        }
+        Ok(Some(png.to_string_lossy().into_owned()))
+    }
+
+    /// Every image converted so far.
+    pub fn into_converted(self) -> Vec<Converted> {
+        self.converted
+    }
+
+    // render the SVG at the converter's DPI, as a PNG recording that DPI so
+    // the image keeps its size in the document
+    fn rasterise(&mut self, data: &[u8], resources_dir: Option<&Path>) -> Result<Vec<u8>> {
+        let options = self.options.get_or_insert_with(|| {
+            let mut options = usvg::Options::default();
+            options.fontdb_mut().load_system_fonts();
+            options
+        });
+        // images referenced by the SVG are relative to the SVG itself
+        options.resources_dir = resources_dir.map(Path::to_path_buf);
+        let tree = usvg::Tree::from_data(data, options)?;
+
+        let scale = self.dpi as f32 / WORD_DPI;
+        let size = tree
+            .size()
+            .to_int_size()
+            .scale_by(scale)
+            .context("The image has no size")?;
+        let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())
+            .context("The image is too large to convert")?;
+        resvg::render(
+            &tree,
+            tiny_skia::Transform::from_scale(scale, scale),
+            &mut pixmap.as_mut(),
+        );
+
+        let rgba: Vec<u8> = pixmap
+            .pixels()
+            .iter()
+            .flat_map(|p| {
+                let c = p.demultiply();
+                [c.red(), c.green(), c.blue(), c.alpha()]
+            })
+            .collect();
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, size.width(), size.height());
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let per_meter = (self.dpi as f64 / 0.0254).round() as u32;
+        encoder.set_pixel_dims(Some(png::PixelDimensions {
+            xppu: per_meter,
+            yppu: per_meter,
+            unit: png::Unit::Meter,
+        }));
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&rgba)?;
+        writer.finish()?;
+        Ok(png)
+    }
+}
+
+/// Add the original SVG of every converted image to the package, linked from
+/// the pictures showing its PNG.
+pub fn embed(package: &mut Package, converted: &[Converted]) -> Result<()> {
+    if converted.is_empty() {
+        return Ok(());
+    }
+    let pngs = converted
+        .iter()
+        .map(|c| fs::read(&c.png).map(|data| (data, &c.svg)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut rels = package.xml(DOCUMENT_RELS)?;
+    let mut types = package.xml(CONTENT_TYPES_XML)?;
+    let image = format!("{}/image", RELATIONSHIP_TYPES);
+    // pandoc copies each PNG into the package as it is, so a picture's
+    // source is found by its bytes
+    let mut svg_ids = Vec::new();
+    for rel in relationships(&rels) {
+        if rel.kind != image || rel.external {
+            continue;
+        }
+        let name = resolve("word", &rel.target);
+        let Some(data) = package.part(&name) else {
+            continue;
+        };
+        let Some((_, svg)) = pngs.iter().find(|(png, _)| png.as_slice() == data) else {
+            continue;
+        };
+        let svg_data =
+            fs::read(svg).with_context(|| format!("Unable to read {}", svg.display()))?;
+        let svg_name = unused_name(package, &format!("{}.svg", name.trim_end_matches(".png")));
+        package.set_part(&svg_name, svg_data);
+        add_content_type(&mut types, &svg_name, "image/svg+xml");
+        let id = add_relationship(&mut rels, &image, &relative("word", &svg_name), false);
+        svg_ids.push((rel.id, id));
+    }
+
+    let xml = package.xml(DOCUMENT_XML)?;
+    let xml = EMPTY_BLIP.replace_all(&xml, |c: &Captures| {
+        match svg_ids.iter().find(|(png, _)| *png == c[2]) {
+            Some((_, svg)) => format!(
+                r#"<a:blip{}r:embed="{}"{}><a:extLst><a:ext uri="{}"><asvg:svgBlip xmlns:asvg="{}" r:embed="{}"/></a:ext></a:extLst></a:blip>"#,
+                &c[1], &c[2], &c[3], SVG_EXTENSION_URI, SVG_NAMESPACE, svg
+            ),
+            None => c[0].to_string(),
+        }
+    });
+    package.set_part(DOCUMENT_XML, xml.into_owned().into_bytes());
+    package.set_part(DOCUMENT_RELS, rels.into_bytes());
+    package.set_part(CONTENT_TYPES_XML, types.into_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{create_files, scratch_dir};
+
+    #[test]
+    fn svgs_are_linked_from_pictures_of_their_pngs() {
+        let dir = scratch_dir("svg-embed");
+        create_files(&dir, &["a.svg", "a.png"]);
+        let converted = [Converted {
+            svg: dir.join("a.svg"),
+            png: dir.join("a.png"),
+        }];
+        let png = fs::read_to_string(dir.join("a.png")).unwrap();
+        let image = format!("{}/image", RELATIONSHIP_TYPES);
+        let mut package = Package::from_parts(&[
+            (
+                CONTENT_TYPES_XML,
+                r#"<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types"><Default Extension="png" ContentType="image/png"/></Types>"#,
+            ),
+            (
+                DOCUMENT_XML,
+                r#"<w:document><w:body><w:p><a:blip r:embed="rId1" /></w:p><w:p><a:blip r:embed="rId2"/></w:p></w:body></w:document>"#,
+            ),
+            (
+                DOCUMENT_RELS,
+                &format!(
+                    r#"<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="{}" Target="media/rId1.png"/><Relationship Id="rId2" Type="{}" Target="media/rId2.png"/></Relationships>"#,
+                    image, image
+                ),
+            ),
+            ("word/media/rId1.png", &png),
+            ("word/media/rId2.png", "another image"),
+        ]);
+
+        embed(&mut package, &converted).unwrap();
+        assert_eq!(
+            package.xml(DOCUMENT_XML).unwrap(),
+            format!(
+                r#"<w:document><w:body><w:p><a:blip r:embed="rId1"><a:extLst><a:ext uri="{}"><asvg:svgBlip xmlns:asvg="{}" r:embed="rId3"/></a:ext></a:extLst></a:blip></w:p><w:p><a:blip r:embed="rId2"/></w:p></w:body></w:document>"#,
+                SVG_EXTENSION_URI, SVG_NAMESPACE
+            )
+        );
+        assert_eq!(package.part("word/media/rId1.svg"), Some(&b"a.svg"[..]));
+        assert!(package.part("word/media/rId2.svg").is_none());
+        let rels = relationships(&package.xml(DOCUMENT_RELS).unwrap());
+        assert_eq!(
+            (rels[2].id.as_str(), rels[2].target.as_str()),
+            ("rId3", "media/rId1.svg")
+        );
+        assert!(package
+            .xml(CONTENT_TYPES_XML)
+            .unwrap()
+            .contains(r#"ContentType="image/svg+xml""#));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+//! Substitution of `{placeholder}` values in filenames and metadata.
+
+use chrono::{format::Item, format::StrftimeItems, DateTime, Local};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// Set to override the `version` of `[output.docx]`.
+pub const VERSION_ENV: &str = "MDBOOK_DOCX_VERSION";
+
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// The values placeholders are replaced with, fixed for the whole build so
+/// every document sees the same date.
+pub struct Values {
+    version: Option<String>,
+    now: DateTime<Local>,
+    git_commit: Option<String>,
+}
+
+impl Values {
+    pub fn new(root: &Path, version: Option<&str>) -> Self {
+        Self {
+            version: env::var(VERSION_ENV)
+                .ok()
+                .filter(|v| !v.is_empty())
+                .or_else(|| version.map(str::to_string)),
+            now: Local::now(),
+            git_commit: git_commit(root),
+        }
+    }
+
+    /// Replace every placeholder in `template`. `{{` and `}}` stand for
+    /// literal braces.
+    pub fn expand(&self, template: &str) -> Result<String, String> {
+        let mut out = String::new();
+        let mut rest = template;
+        while let Some(at) = rest.find(['{', '}']) {
+            out.push_str(&rest[..at]);
+            let brace = &rest[at..at + 1];
+            if rest[at + 1..].starts_with(brace) {
+                out.push_str(brace);
+                rest = &rest[at + 2..];
+                continue;
+            }
+            if brace == "}" {
+                return Err(format!("unmatched \"}}\" in \"{}\"", template));
+            }
+            let Some(len) = rest[at..].find('}') else {
+                return Err(format!("unclosed \"{{\" in \"{}\"", template));
+            };
+            let placeholder = &rest[at + 1..at + len];
+            out.push_str(
+                &self
+                    .value(placeholder)
+                    .map_err(|e| format!("{} in \"{}\"", e, template))?,
+            );
+            rest = &rest[at + len + 1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    fn value(&self, placeholder: &str) -> Result<String, String> {
+        let (name, argument) = match placeholder.split_once(':') {
+            Some((name, argument)) => (name, Some(argument)),
+            None => (placeholder, None),
+        };
+        match (name, argument) {
+            ("version", None) => self.version.clone().ok_or_else(|| {
+                format!(
+                    "{{version}} needs a version set with `version` in [output.docx] or the {} environment variable",
+                    VERSION_ENV
+                )
+            }),
+            ("date", format) => {
+                let format = format.unwrap_or(DEFAULT_DATE_FORMAT);
+                if StrftimeItems::new(format).any(|item| item == Item::Error) {
+                    return Err(format!("invalid date format \"{}\"", format));
+                }
+                Ok(self.now.format(format).to_string())
+            }
+            ("git_commit", None) => self
+                .git_commit
+                .clone()
+                .ok_or_else(|| "{git_commit} needs the book to be in a git repository".to_string()),
+            _ => Err(format!("unknown placeholder \"{{{}}}\"", placeholder)),
+        }
+    }
+}
+
+// the abbreviated hash of the commit checked out in the git repository
+// holding `root`, read from .git directly rather than running git
+fn git_commit(root: &Path) -> Option<String> {
+    let git_dir = root
+        .ancestors()
+        .find_map(|dir| git_dir(&dir.join(".git")))?;
+    let head = fs::read_to_string(git_dir.join("HEAD")).ok()?;
+    let head = head.trim();
+    let hash = match head.strip_prefix("ref:") {
+        Some(reference) => resolve_ref(&git_dir, reference.trim())?,
+        None => head.to_string(),
+    };
+    Some(hash.chars().take(7).collect())
+}
+
+// .git is a directory, or a file pointing at one for worktrees and submodules
+fn git_dir(path: &Path) -> Option<PathBuf> {
+    if path.is_dir() {
+        return Some(path.to_path_buf());
+    }
+    let pointer = fs::read_to_string(path).ok()?;
+    let dir = PathBuf::from(pointer.trim().strip_prefix("gitdir:")?.trim());
+    Some(match dir.is_absolute() {
+        true => dir,
+        false => path.parent()?.join(dir),
+    })
+}
+
+fn resolve_ref(git_dir: &Path, reference: &str) -> Option<String> {
+    // a worktree keeps its branches in the main repository
+    let common = fs::read_to_string(git_dir.join("commondir"))
+        .ok()
+        .map(|dir| git_dir.join(dir.trim()));
+    for dir in [Some(git_dir.to_path_buf()), common].into_iter().flatten() {
+        if let Ok(hash) = fs::read_to_string(dir.join(reference)) {
+            return Some(hash.trim().to_string());
+        }
+        // refs that have not changed since `git gc` live in packed-refs
+        if let Ok(packed) = fs::read_to_string(dir.join("packed-refs")) {
+            let found = packed
+                .lines()
+                .filter_map(|line| line.split_once(' '))
+                .find(|(_, name)| *name == reference);
+            if let Some((hash, _)) = found {
+                return Some(hash.to_string());
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn values() -> Values {
+        Values {
+            version: Some("1.2.0".to_string()),
+            now: Local.with_ymd_and_hms(2024, 3, 5, 14, 30, 0).unwrap(),
+            git_commit: Some("abc1234".to_string()),
+        }
+    }
+
+    #[test]
+    fn placeholders() {
+        let values = values();
+        assert_eq!(
+            values.expand("guide-{version}-{git_commit}.docx"),
+            Ok("guide-1.2.0-abc1234.docx".to_string())
+        );
+        assert_eq!(values.expand("{date}"), Ok("2024-03-05".to_string()));
+        assert_eq!(
+            values.expand("{date:%d %B %Y}"),
+            Ok("05 March 2024".to_string())
+        );
+        assert_eq!(values.expand("plain.docx"), Ok("plain.docx".to_string()));
+    }
+
+    #[test]
+    fn escaped_braces() {
+        let values = values();
+        assert_eq!(
+            values.expand("{{version}} is {version}"),
+            Ok("{version} is 1.2.0".to_string())
+        );
+        assert_eq!(values.expand("}}{{"), Ok("}{".to_string()));
+    }
+
+    #[test]
+    fn unbalanced_braces() {
+        let values = values();
+        assert_eq!(
+            values.expand("guide-{version"),
+            Err("unclosed \"{\" in \"guide-{version\"".to_string())
+        );
+        assert_eq!(
+            values.expand("guide}"),
+            Err("unmatched \"}\" in \"guide}\"".to_string())
+        );
+    }
+
+    #[test]
+    fn invalid_placeholders() {
+        let values = values();
+        assert_eq!(
+            values.expand("{date:%Q}"),
+            Err("invalid date format \"%Q\" in \"{date:%Q}\"".to_string())
+        );
+        assert_eq!(
+            values.expand("{name}"),
+            Err("unknown placeholder \"{name}\" in \"{name}\"".to_string())
+        );
+        assert_eq!(
+            values.expand("{version:x}"),
+            Err("unknown placeholder \"{version:x}\" in \"{version:x}\"".to_string())
+        );
+
+        let unset = Values {
+            version: None,
+            git_commit: None,
+            ..values
+        };
+        assert!(unset
+            .expand("{version}")
+            .unwrap_err()
+            .starts_with("{version} needs"));
+        assert!(unset
+            .expand("{git_commit}")
+            .unwrap_err()
+            .starts_with("{git_commit} needs"));
+    }
+}
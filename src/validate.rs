@@ -0,0 +1,183 @@
+//! Checks run against a document before pandoc is invoked.
+
+use crate::markdown;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+/// A single problem found while validating a document.
+#[derive(Debug)]
+pub enum Problem {
+    /// an include, sections or parts selector matched no chapter
+    UnmatchedSelector(String),
+    /// an image in a chapter points at a file that does not exist
+    MissingImage { chapter: PathBuf, target: String },
+    /// a link in a chapter points at a file that does not exist
+    BrokenLink { chapter: PathBuf, target: String },
+    /// a file named in the document configuration does not exist
+    MissingFile { option: &'static str, path: PathBuf },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::UnmatchedSelector(selector) => {
+                write!(f, "include {} matched no chapters", selector)
+            }
+            Problem::MissingImage { chapter, target } => {
+                write!(f, "{}: image \"{}\" not found", chapter.display(), target)
+            }
+            Problem::BrokenLink { chapter, target } => {
+                write!(
+                    f,
+                    "{}: link target \"{}\" not found",
+                    chapter.display(),
+                    target
+                )
+            }
+            Problem::MissingFile { option, path } => {
+                write!(f, "{} file \"{}\" not found", option, path.display())
+            }
+        }
+    }
+}
+
+/// Every problem found while validating a document.
+#[derive(Debug)]
+pub struct ValidationError {
+    pub problems: Vec<Problem>,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} problem(s) found during validation:",
+            self.problems.len()
+        )?;
+        for problem in &self.problems {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Check that every local image and link target in a chapter exists. Targets
+/// are looked up relative to the chapter, then relative to the src dir.
+/// Images may also sit in one of the `resource_paths`.
+pub fn check_targets(
+    src_dir: &Path,
+    resource_paths: &[PathBuf],
+    chapter: &Path,
+    content: &str,
+) -> Vec<Problem> {
+    let chapter_dir = src_dir.join(chapter.parent().unwrap_or_else(|| Path::new("")));
+    let exists = |target: &Path| chapter_dir.join(target).exists() || src_dir.join(target).exists();
+    let image_exists = |target: &Path| {
+        exists(target) || resource_paths.iter().any(|dir| dir.join(target).exists())
+    };
+
+    let mut problems = Vec::new();
+    for target in markdown::images(content) {
+        if local_path(&target).is_some_and(|path| !image_exists(&path)) {
+            problems.push(Problem::MissingImage {
+                chapter: chapter.to_path_buf(),
+                target,
+            });
+        }
+    }
+    for target in markdown::links(content) {
+        // the web book links to the .html page rendered from each chapter
+        let path = local_path(&target).map(|path| match path.extension() {
+            Some(ext) if ext == "html" => path.with_extension("md"),
+            _ => path,
+        });
+        if path.is_some_and(|path| !exists(&path)) {
+            problems.push(Problem::BrokenLink {
+                chapter: chapter.to_path_buf(),
+                target,
+            });
+        }
+    }
+    problems
+}
+
+/// The file a relative link or image destination refers to, without any
+/// fragment or query. `None` for URLs, absolute paths and fragment-only links.
+pub fn local_path(dest: &str) -> Option<PathBuf> {
+    let path = dest.split(['#', '?']).next().unwrap_or_default();
+    let has_scheme = path
+        .split_once(':')
+        .is_some_and(|(scheme, _)| !scheme.contains('/'));
+    if path.is_empty() || has_scheme || path.starts_with('/') {
+        return None;
+    }
+    Some(PathBuf::from(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{create_files, scratch_dir};
+    use std::fs;
+
+    #[test]
+    fn local_paths() {
+        let cases = [
+            ("img/a.png", Some("img/a.png")),
+            ("./a.md#setup", Some("./a.md")),
+            ("../b.html?tab=1", Some("../b.html")),
+            ("dir/time:10.md", Some("dir/time:10.md")),
+            ("#setup", None),
+            ("?q", None),
+            ("", None),
+            ("/img/a.png", None),
+            ("https://example.com/a.md", None),
+            ("mailto:team@example.com", None),
+            ("data:image/png;base64,AAAA", None),
+        ];
+        for (dest, expected) in cases {
+            assert_eq!(local_path(dest), expected.map(PathBuf::from), "{}", dest);
+        }
+    }
+
+    #[test]
+    fn targets_are_found_beside_the_chapter_in_src_or_on_the_resource_paths() {
+        let root = scratch_dir("check-targets");
+        create_files(
+            &root,
+            &[
+                "src/ch/img/beside.png",
+                "src/ch/other.md",
+                "src/img/root.png",
+                "src/intro.md",
+                "res/logo.png",
+            ],
+        );
+        let src = root.join("src");
+        let content = concat!(
+            "![](img/beside.png) ![](img/root.png) ![](logo.png) ![](../img/root.png)\n",
+            "![](img/missing.png) ![](https://example.com/a.png) ![](/abs.png)\n\n",
+            "[](other.md) [](other.html#part) [](intro.md) [](../intro.html) [](#top)\n",
+            "[](missing.md) [](gone.html) [](logo.png) [](https://example.com/a.md)\n",
+            "[](/abs.md) [](mailto:team@example.com)\n",
+        );
+
+        let problems = check_targets(&src, &[root.join("res")], Path::new("ch/one.md"), content);
+        let found: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            found,
+            [
+                r#"ch/one.md: image "img/missing.png" not found"#,
+                r#"ch/one.md: link target "missing.md" not found"#,
+                r#"ch/one.md: link target "gone.html" not found"#,
+                // only images are looked for on the resource paths
+                r#"ch/one.md: link target "logo.png" not found"#,
+            ]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+}